@@ -9,23 +9,79 @@ endif
 
 " Create commands for RPC calls handled by main.clj.
 command! -nargs=1 ConjureAdd call rpcnotify(s:jobid, "add", <q-args>)
+command! -nargs=0 ConjureAutoAdd call rpcnotify(s:jobid, "auto_add")
 command! -nargs=1 ConjureRemove call rpcnotify(s:jobid, "remove", <q-args>)
 command! -nargs=0 ConjureRemoveAll call rpcnotify(s:jobid, "remove_all")
+command! -nargs=0 ConjureReconnectAll call rpcnotify(s:jobid, "reconnect_all")
+command! -nargs=+ ConjureBindings call rpcnotify(s:jobid, "bindings", <q-args>)
+command! -nargs=+ ConjureRename call rpcnotify(s:jobid, "rename", <f-args>)
 command! -nargs=0 ConjureStatus call rpcnotify(s:jobid, "status")
+command! -nargs=? ConjureRoute call rpcnotify(s:jobid, "route", <q-args>)
 
+command! -nargs=0 ConjureToggleDryRun call rpcnotify(s:jobid, "toggle_dry_run")
+command! -nargs=? ConjureToggleReflection call rpcnotify(s:jobid, "toggle_reflection", <q-args>)
 command! -nargs=1 ConjureEval call rpcnotify(s:jobid, "eval", <q-args>)
+command! -nargs=+ ConjureEvalOn call rpcnotify(s:jobid, "eval_on", <q-args>)
+command! -nargs=+ ConjureEvalIn call rpcnotify(s:jobid, "eval_in", <q-args>)
+command! -nargs=? ConjureInNs call rpcnotify(s:jobid, "in_ns", <q-args>)
 command! -range   ConjureEvalSelection call rpcnotify(s:jobid, "eval_selection")
 command! -nargs=0 ConjureEvalCurrentForm call rpcnotify(s:jobid, "eval_current_form")
 command! -nargs=0 ConjureEvalRootForm call rpcnotify(s:jobid, "eval_root_form")
 command! -nargs=0 ConjureEvalBuffer call rpcnotify(s:jobid, "eval_buffer")
-command! -nargs=1 ConjureLoadFile call rpcnotify(s:jobid, "load_file", <q-args>)
+command! -nargs=0 ConjureEvalReplace call rpcnotify(s:jobid, "eval_replace")
+command! -nargs=0 ConjureEvalComment call rpcnotify(s:jobid, "eval_comment")
+command! -nargs=? ConjureEvalLast call rpcnotify(s:jobid, "eval_last", <q-args>)
+command! -nargs=? ConjureMacroexpand call rpcnotify(s:jobid, "macroexpand", "full", <q-args>)
+command! -nargs=? ConjureMacroexpand1 call rpcnotify(s:jobid, "macroexpand", "one", <q-args>)
+command! -nargs=? ConjureMacroexpandAll call rpcnotify(s:jobid, "macroexpand", "all", <q-args>)
+command! -nargs=? ConjureLoadFile call rpcnotify(s:jobid, "load_file", <q-args>)
+command! -nargs=+ ConjureCustom call rpcnotify(s:jobid, "custom", <q-args>, expand("<cword>"))
+command! -nargs=0 ConjureTasks call rpcnotify(s:jobid, "tasks")
+command! -nargs=1 ConjureRunTask call rpcnotify(s:jobid, "run_task", <q-args>)
+command! -nargs=? ConjureDevSetup call rpcnotify(s:jobid, "dev_setup", <q-args>)
+command! -nargs=? ConjureSystemStart call rpcnotify(s:jobid, "system", "start " . <q-args>)
+command! -nargs=? ConjureSystemReset call rpcnotify(s:jobid, "system", "reset " . <q-args>)
+command! -nargs=? ConjureSystemStop call rpcnotify(s:jobid, "system", "stop " . <q-args>)
+command! -nargs=+ ConjureAddLib call rpcnotify(s:jobid, "add_lib", <q-args>)
+command! -nargs=? ConjureClasspath call rpcnotify(s:jobid, "classpath", <q-args>)
+command! -nargs=+ ConjureUnmap call rpcnotify(s:jobid, "unmap", <q-args>)
+command! -nargs=0 ConjureUnmapStale call rpcnotify(s:jobid, "unmap_stale")
+command! -nargs=1 ConjureUnalias call rpcnotify(s:jobid, "unalias", <q-args>)
+command! -nargs=0 ConjureUnusedRequires call rpcnotify(s:jobid, "unused_requires")
+command! -nargs=* ConjureStdin call rpcnotify(s:jobid, "stdin", <q-args>)
+command! -nargs=? ConjureInterrupt call rpcnotify(s:jobid, "interrupt", <q-args>)
 
 command! -nargs=1 ConjureDefinition call rpcnotify(s:jobid, "definition", <q-args>)
+command! -nargs=1 ConjureUsages call rpcnotify(s:jobid, "usages", <q-args>)
 command! -nargs=1 ConjureDoc call rpcnotify(s:jobid, "doc", <q-args>)
+command! -nargs=1 ConjureSource call rpcnotify(s:jobid, "source", <q-args>)
+command! -nargs=1 ConjureExamples call rpcnotify(s:jobid, "examples", <q-args>)
+command! -nargs=? ConjureNsPublics call rpcnotify(s:jobid, "ns_publics", <q-args>)
+command! -nargs=1 ConjureSpec call rpcnotify(s:jobid, "spec", <q-args>)
+command! -nargs=1 ConjureApropos call rpcnotify(s:jobid, "apropos", <q-args>)
+command! -nargs=? ConjureArglists call conjure#echo_arglists(<q-args>)
+command! -nargs=1 ConjureInspect call rpcnotify(s:jobid, "inspect", <q-args>)
 command! -nargs=0 ConjureOpenLog call rpcnotify(s:jobid, "open_log")
 command! -nargs=0 ConjureCloseLog call rpcnotify(s:jobid, "close_log")
+command! -nargs=? ConjureStackTrace call rpcnotify(s:jobid, "stack_trace", <q-args>)
+command! -nargs=* ConjureYankResult call rpcnotify(s:jobid, "yank_result", <q-args>)
+command! -nargs=0 ConjureToggleLogFollow call rpcnotify(s:jobid, "toggle_log_follow")
+command! -nargs=0 ConjureLogNextEval call conjure#log_jump("W")
+command! -nargs=0 ConjureLogPrevEval call conjure#log_jump("bW")
+command! -nargs=0 ConjureClearLog call rpcnotify(s:jobid, "clear_log")
+command! -nargs=+ ConjureLogOnly call rpcnotify(s:jobid, "log_only", <q-args>)
+command! -nargs=+ ConjureLogHide call rpcnotify(s:jobid, "log_hide", <q-args>)
+command! -nargs=0 ConjureLogShowAll call rpcnotify(s:jobid, "log_show_all")
+command! -nargs=0 ConjureExpandLast call rpcnotify(s:jobid, "expand_last")
+command! -nargs=? ConjureExpandElision call rpcnotify(s:jobid, "expand_elision", <q-args>)
 command! -nargs=* ConjureRunTests call rpcnotify(s:jobid, "run_tests", <q-args>)
+command! -nargs=0 ConjureRunCurrentTest call rpcnotify(s:jobid, "run_current_test")
+command! -nargs=0 ConjureRetestFailed call rpcnotify(s:jobid, "retest_failed")
+command! -nargs=0 ConjureJumpToFailure call rpcnotify(s:jobid, "jump_to_failure")
+command! -nargs=? ConjureRunProjectTests call rpcnotify(s:jobid, "run_project_tests", <q-args>)
 command! -nargs=? ConjureRunAllTests call rpcnotify(s:jobid, "run_all_tests", <q-args>)
+command! -nargs=0 ConjureRefresh call rpcnotify(s:jobid, "refresh")
+command! -nargs=0 ConjureRefreshAll call rpcnotify(s:jobid, "refresh_all")
 
 " Default mappings if not disabled.
 if !exists("g:conjure_default_mappings") || g:conjure_default_mappings
@@ -36,18 +92,69 @@ if !exists("g:conjure_default_mappings") || g:conjure_default_mappings
     autocmd FileType clojure nnoremap <buffer> <localleader>rr :ConjureEvalRootForm<cr>
     autocmd FileType clojure vnoremap <buffer> <localleader>re :ConjureEvalSelection<cr>
     autocmd FileType clojure nnoremap <buffer> <localleader>rf :ConjureEvalBuffer<cr>
-    autocmd FileType clojure nnoremap <buffer> <localleader>rF :ConjureLoadFile <c-r>=expand('%:p')<cr><cr>
+    autocmd FileType clojure nnoremap <buffer> <localleader>r. :ConjureEvalLast<cr>
+    autocmd FileType clojure nnoremap <buffer> <localleader>rF :ConjureLoadFile<cr>
+    autocmd FileType clojure nnoremap <buffer> <localleader>rd :ConjureDevSetup<cr>
     autocmd FileType clojure nnoremap <buffer> <localleader>rs :ConjureStatus<cr>
+    autocmd FileType clojure nnoremap <buffer> <localleader>ri :ConjureInterrupt<cr>
     autocmd FileType clojure nnoremap <buffer> <localleader>rl :ConjureOpenLog<cr>
     autocmd FileType clojure nnoremap <buffer> <localleader>rq :ConjureCloseLog<cr>
     autocmd FileType clojure nnoremap <buffer> <localleader>rt :ConjureRunTests<cr>
     autocmd FileType clojure nnoremap <buffer> <localleader>rT :ConjureRunAllTests<cr>
     autocmd FileType clojure nnoremap <buffer> K :ConjureDoc <c-r><c-w><cr>
+    autocmd FileType clojure nnoremap <buffer> <localleader>rv :ConjureInspect <c-r><c-w><cr>
     autocmd FileType clojure nnoremap <buffer> gd :ConjureDefinition <c-r><c-w><cr>
+    autocmd FileType clojure nnoremap <buffer> <localleader>ru :ConjureUnmap <c-r><c-w><cr>
     autocmd FileType clojure setlocal omnifunc=conjure#omnicomplete
   augroup END
 endif
 
+" Signs marking each test as passed or failed after a run.
+sign define ConjureTestPass text=✓ texthl=String
+sign define ConjureTestFail text=✗ texthl=ErrorMsg
+
+" The log is a Clojure buffer so results are highlighted as code, these pick
+" the prefixed stderr, output and Conjure lines out from the plain comments.
+" Link the groups to something else in your config to change them.
+function! conjure#log_syntax()
+  syntax match ConjureLogOut /^; \S\+\/\(out\|test-out\) | .*$/
+  syntax match ConjureLogErr /^; \S\+\/err | .*$/
+  syntax match ConjureLogInfo /^; conjure\/out | .*$/
+  syntax match ConjureLogHeader /^; \S\+\/\S\+\( (.*)\)\? ⤸$/
+  syntax match ConjureLogRet /^; \S\+\/ret\( (.*)\)\? ⤸$/
+  syntax match ConjureLogTap /^; \S\+\/tap\( (.*)\)\? ⤸$/
+  highlight default link ConjureLogOut Comment
+  highlight default link ConjureLogErr ErrorMsg
+  highlight default link ConjureLogInfo Special
+  highlight default link ConjureLogHeader Title
+  highlight default link ConjureLogRet Function
+  highlight default link ConjureLogTap Type
+endfunction
+
+" Every evaluation starts with an eval line in the log, these move between
+" them in the log window wherever you are.
+function! conjure#log_jump(flags)
+  let win = bufwinid("/tmp/conjure.cljc")
+  if win == -1
+    echo "The Conjure log isn't open"
+  else
+    call win_execute(win, 'call search("^; \\S\\+\\/eval | ", "' . a:flags . '") | normal! zt')
+  endif
+endfunction
+
+augroup conjure_log
+  autocmd!
+  autocmd FileType clojure if expand("<afile>:p") =~# "conjure.cljc$" | call conjure#log_syntax() | endif
+  autocmd FileType clojure if expand("<afile>:p") =~# "conjure.cljc$" | nnoremap <buffer> ]] :ConjureLogNextEval<cr>| endif
+  autocmd FileType clojure if expand("<afile>:p") =~# "conjure.cljc$" | nnoremap <buffer> [[ :ConjureLogPrevEval<cr>| endif
+augroup END
+
+" Opt in to running the tests for a buffer whenever it's saved.
+augroup conjure_test_on_save
+  autocmd!
+  autocmd BufWritePost *.clj,*.clj[cs] if get(g:, 'conjure_test_on_save', 0) | call rpcnotify(s:jobid, "test_on_save") | endif
+augroup END
+
 " Handles all stderr from the Clojure process.
 " Simply prints it in red.
 function! conjure#on_stderr(jobid, lines, event) dict
@@ -106,6 +213,36 @@ function! conjure#completions(base)
   return rpcrequest(s:jobid, "completions", a:base)
 endfunction
 
+" The symbol at the head of the call the cursor is inside, even if it hasn't
+" been closed yet.
+function! conjure#call_head()
+  let [line, col] = searchpairpos('(', '', ')', 'bnW')
+  if line == 0
+    return ""
+  endif
+  return matchstr(getline(line)[col :], '^[^ \t()[\]{}"]\+')
+endfunction
+
+" Arglists of the given symbol or the call the cursor is in, v:null if
+" there aren't any. Handy for signature hints.
+function! conjure#arglists(...)
+  let name = a:0 && a:1 != "" ? a:1 : conjure#call_head()
+  return rpcrequest(s:jobid, "arglists", name)
+endfunction
+
+function! conjure#echo_arglists(name)
+  let arglists = conjure#arglists(a:name)
+  if type(arglists) == v:t_string
+    echo arglists
+  endif
+endfunction
+
+" The top level definitions in the current buffer as a list of dictionaries
+" with name, kind and line keys.
+function! conjure#document_symbols()
+  return rpcrequest(s:jobid, "document_symbols")
+endfunction
+
 function! conjure#get_rpc_port()
   return rpcrequest(s:jobid, "get_rpc_port")
 endfunction
@@ -5,52 +5,93 @@ local function ends_with(str, ending)
   return ending == "" or str:sub(-#ending) == ending
 end
 
--- Find the log window and buffer if they exist.
-local function find_log (log_buf_name)
-  local tabpage = vim.api.nvim_get_current_tabpage()
-  local wins = vim.api.nvim_tabpage_list_wins(tabpage)
-
-  for _, win in ipairs(wins) do
-    local buf = vim.api.nvim_win_get_buf(win)
-    local buf_name = vim.api.nvim_buf_get_name(buf)
-
-    -- OSX symlinks /tmp to /private/tmp so we check the suffix instead.
-    if ends_with(buf_name, log_buf_name) then
-      return {win = win, buf = buf}
+-- Find the log window and buffer if they exist. The log gets its own tab in
+-- the tab layout so we look through every tabpage for that one.
+local function find_log (log_buf_name, all_tabs)
+  local tabpages = all_tabs and vim.api.nvim_list_tabpages() or {vim.api.nvim_get_current_tabpage()}
+
+  for _, tabpage in ipairs(tabpages) do
+    for _, win in ipairs(vim.api.nvim_tabpage_list_wins(tabpage)) do
+      local buf = vim.api.nvim_win_get_buf(win)
+      local buf_name = vim.api.nvim_buf_get_name(buf)
+
+      -- OSX symlinks /tmp to /private/tmp so we check the suffix instead.
+      if ends_with(buf_name, log_buf_name) then
+        return {win = win, buf = buf}
+      end
     end
   end
 
   return nil
 end
 
--- Find or create (and then find again) the log window and buffer.
-function conjure.upsert_log (log_buf_name, width, focus, resize)
-  local result = find_log(log_buf_name)
+local function set_log_options (buf)
+  vim.api.nvim_buf_set_option(buf, "buftype", "nofile")
+  vim.api.nvim_buf_set_option(buf, "bufhidden", "hide")
+  vim.api.nvim_buf_set_option(buf, "swapfile", false)
+  vim.api.nvim_buf_set_option(buf, "buflisted", false)
+end
+
+-- The log buffer without a window, for when the log stays in the background.
+local function find_or_create_log_buf (log_buf_name)
+  local buf = vim.api.nvim_call_function("bufnr", {log_buf_name})
+  if buf == -1 then
+    buf = vim.api.nvim_call_function("bufadd", {log_buf_name})
+    vim.api.nvim_call_function("bufload", {buf})
+    set_log_options(buf)
+  end
+
+  return {buf = buf}
+end
+
+-- Find or create (and then find again) the log window and buffer. The layout
+-- is "vertical", "horizontal", "tab" or "hidden", which never opens a window
+-- unless we're asked to focus it.
+function conjure.upsert_log (log_buf_name, size, focus, resize, layout)
+  local result = find_log(log_buf_name, layout == "tab")
   if result then
     if focus == true then
       vim.api.nvim_set_current_win(result.win)
     end
 
     if resize == true then
-      vim.api.nvim_win_set_width(result.win, width)
+      if layout == "horizontal" then
+        vim.api.nvim_win_set_height(result.win, size)
+      elseif layout ~= "tab" then
+        vim.api.nvim_win_set_width(result.win, size)
+      end
     end
 
     return result
+  elseif layout == "hidden" and focus ~= true then
+    return find_or_create_log_buf(log_buf_name)
   else
-    vim.api.nvim_command("botright " .. width .. "vsplit " .. log_buf_name)
-    vim.api.nvim_command("setlocal winfixwidth")
-    vim.api.nvim_command("setlocal buftype=nofile")
-    vim.api.nvim_command("setlocal bufhidden=hide")
+    local tab = vim.api.nvim_tabpage_get_number(vim.api.nvim_get_current_tabpage())
+
+    if layout == "tab" then
+      vim.api.nvim_command("$tabnew " .. log_buf_name)
+    elseif layout == "horizontal" then
+      vim.api.nvim_command("botright " .. size .. "split " .. log_buf_name)
+      vim.api.nvim_command("setlocal winfixheight")
+    else
+      vim.api.nvim_command("botright " .. size .. "vsplit " .. log_buf_name)
+      vim.api.nvim_command("setlocal winfixwidth")
+    end
+
+    set_log_options(vim.api.nvim_get_current_buf())
     vim.api.nvim_command("setlocal nowrap")
-    vim.api.nvim_command("setlocal noswapfile")
-    vim.api.nvim_command("setlocal nobuflisted")
     vim.api.nvim_command("setlocal nospell")
+    vim.api.nvim_command("setlocal foldmethod=manual")
 
     if focus ~= true then
-      vim.api.nvim_command("wincmd p")
+      if layout == "tab" then
+        vim.api.nvim_command("tabnext " .. tab)
+      else
+        vim.api.nvim_command("wincmd p")
+      end
     end
 
-    return find_log(log_buf_name)
+    return find_log(log_buf_name, layout == "tab")
   end
 end
 
@@ -63,4 +104,112 @@ function conjure.close_log (log_buf_name)
   end
 end
 
+-- Display the lines in a floating window under the cursor, it closes itself
+-- as soon as the cursor moves.
+function conjure.open_float (lines)
+  local buf = vim.api.nvim_create_buf(false, true)
+  vim.api.nvim_buf_set_lines(buf, 0, -1, false, lines)
+
+  local width = 1
+  for _, line in ipairs(lines) do
+    width = math.max(width, #line)
+  end
+
+  local win = vim.api.nvim_open_win(buf, false, {
+    relative = "cursor",
+    row = 1,
+    col = 0,
+    width = math.min(width, vim.api.nvim_get_option("columns") - 4),
+    height = math.min(#lines, 20),
+    style = "minimal"
+  })
+
+  vim.api.nvim_command("autocmd CursorMoved,CursorMovedI,BufLeave <buffer> ++once lua pcall(vim.api.nvim_win_close, " .. win .. ", true)")
+  return win
+end
+
+local hud = {win = nil, timer = nil}
+
+local function close_hud ()
+  if hud.timer then
+    hud.timer:stop()
+    hud.timer:close()
+    hud.timer = nil
+  end
+
+  if hud.win then
+    pcall(vim.api.nvim_win_close, hud.win, true)
+    hud.win = nil
+  end
+end
+
+-- Display the lines in a small floating window in the top right corner that
+-- closes itself after the timeout, replacing any that's already open.
+function conjure.open_hud (lines, timeout, hl)
+  close_hud()
+
+  local buf = vim.api.nvim_create_buf(false, true)
+  vim.api.nvim_buf_set_lines(buf, 0, -1, false, lines)
+
+  local width = 1
+  for _, line in ipairs(lines) do
+    width = math.max(width, #line)
+  end
+  width = math.min(width, math.floor(vim.api.nvim_get_option("columns") / 2))
+
+  hud.win = vim.api.nvim_open_win(buf, false, {
+    relative = "editor",
+    anchor = "NE",
+    row = 1,
+    col = vim.api.nvim_get_option("columns"),
+    width = width,
+    height = math.min(#lines, 10),
+    style = "minimal",
+    focusable = false
+  })
+  vim.api.nvim_win_set_option(hud.win, "winhl", "Normal:" .. hl)
+
+  hud.timer = vim.loop.new_timer()
+  hud.timer:start(timeout, 0, vim.schedule_wrap(close_hud))
+end
+
+local results_ns = vim.api.nvim_create_namespace("conjure_results")
+
+-- Show a result as virtual text at the end of the line, replacing whatever
+-- result was there before.
+function conjure.set_virtual_text (buf, line, text, hl)
+  vim.api.nvim_buf_clear_namespace(buf, results_ns, line - 1, line)
+  vim.api.nvim_buf_set_virtual_text(buf, results_ns, line - 1, {{text, hl}}, {})
+end
+
+local origins_ns = vim.api.nvim_create_namespace("conjure_origins")
+
+-- Colour the connection part of some log line prefixes, each colour gets a
+-- highlight group of its own the first time we see it.
+function conjure.highlight_origin (buf, start, count, width, color)
+  local group = "ConjureLogOrigin" .. color:sub(2)
+  vim.api.nvim_command("highlight default " .. group .. " guifg=" .. color)
+  for line = start, start + count - 1 do
+    vim.api.nvim_buf_add_highlight(buf, origins_ns, group, line, 0, width)
+  end
+end
+
+-- Place a pass or fail sign on the line of each test result, clearing the
+-- old ones from those buffers first.
+function conjure.place_test_signs (results)
+  local bufs = {}
+  for _, result in ipairs(results) do
+    local buf = vim.api.nvim_call_function("bufnr", {result.file})
+    if buf > 0 then
+      if not bufs[buf] then
+        bufs[buf] = true
+        vim.api.nvim_call_function("sign_unplace", {"conjure_tests", {buffer = buf}})
+      end
+
+      local name = result.passed and "ConjureTestPass" or "ConjureTestFail"
+      vim.api.nvim_call_function("sign_place", {0, "conjure_tests", name, buf, {lnum = result.line}})
+    end
+  end
+end
+
 return conjure
@@ -2,109 +2,751 @@
   "Things the user can do that probably trigger some sort of UI update."
   (:require [clojure.core.async :as a]
             [clojure.string :as str]
+            [clojure.java.io :as io]
+            [clojure.edn :as edn]
             [taoensso.timbre :as log]
             [conjure.prepl :as prepl]
             [conjure.ui :as ui]
             [conjure.nvim :as nvim]
             [conjure.code :as code]
-            [conjure.util :as util]))
+            [conjure.util :as util]
+            [conjure.unrepl :as unrepl]
+            [conjure.clojuredocs :as clojuredocs]))
+
+(defonce ^:private last-evals! (atom {}))
+(defonce ^:private dry-run?! (atom false))
+(defonce ^:private required! (atom #{}))
+(defonce ^:private last-failed! (atom {}))
+(defonce ^:private last-failure! (atom nil))
+(defonce ^:private last-results! (atom {}))
+(defonce ^:private last-errors! (atom {}))
+(def ^:private default-slow-eval-threshold 10000)
+(def ^:private default-hud-timeout 3000)
 
 (defn- current-ctx
-  "An enriched version of the nvim ctx with matching prepl connections."
+  "An enriched version of the nvim ctx with matching prepl connections. A :tag
+  picks that connection instead of matching on the path."
   ([] (current-ctx {}))
-  ([{:keys [silent?] :or {silent? false}}]
+  ([{:keys [silent? tag] :or {silent? false}}]
    (let [ctx (nvim/current-ctx)
-         conns (prepl/conns (:path ctx))]
+         conns (if tag
+                 (some-> (prepl/conn tag) (vector))
+                 (prepl/conns (:path ctx)))]
 
-     (when (and (empty? conns) (not silent?))
+     (when (and (empty? conns) (not silent?) (not tag))
        (ui/error "No matching connections for" (:path ctx)))
 
+     (doseq [{:keys [tag stale?]} conns]
+       (when (and stale? (not silent?))
+         (ui/error tag "isn't responding, this may never return")))
+
      (merge ctx {:conns conns}))))
 
+(defn- dry-run
+  "In dry run mode we log exactly what would have been sent instead of sending
+  it. Returns the result evals should pretend to have received."
+  [conn code]
+  (ui/append {:conn conn, :kind :dry-run, :code? true, :msg code})
+  {:tag :ret, :val [:ok nil]})
+
+(defn- eval-ns
+  "The namespace an eval on the connection will run in, if we know it."
+  [ctx conn]
+  (or (:ns ctx) (:ns conn)))
+
 (defn- wrapped-eval
   "Wraps up code with environment specific padding, sends it off for evaluation
   and blocks until we get a result."
   [ctx {:keys [conn] :as opts}]
-  (let [{:keys [eval-chan ret-chan]} (:chans conn)]
-    (a/>!! eval-chan (code/eval-str ctx opts))
+  (let [{:keys [eval-chan]} (:chans conn)
+        code (code/eval-str (assoc ctx :ns (eval-ns ctx conn)) opts)]
+    (if @dry-run?!
+      (dry-run conn code)
+      (prepl/exclusively
+        conn
+        (fn []
+          (a/>!! eval-chan code)
 
-    ;; ClojureScript requires two evals:
-    ;; * Call in-ns.
-    ;; * Execute the provided code.
-    ;; We throw away the in-ns result first.
-    (when (= (:lang conn) :cljs)
-      (a/<!! ret-chan))
+          ;; ClojureScript requires two evals:
+          ;; * Call in-ns.
+          ;; * Execute the provided code.
+          ;; We throw away the in-ns result first.
+          (when (= (:lang conn) :cljs)
+            (prepl/await-ret conn))
 
-    (a/<!! ret-chan)))
+          (prepl/await-ret conn))))))
 
 (defn- raw-eval
   "Unlike wrapped-eval, it will send the exact code it is given and then block
   for a response."
   [ctx {:keys [conn code]}]
-  (let [{:keys [eval-chan ret-chan]} (:chans conn)]
-    (a/>!! eval-chan code)
-    (a/<!! ret-chan)))
+  (let [{:keys [eval-chan]} (:chans conn)]
+    (if @dry-run?!
+      (dry-run conn code)
+      (prepl/exclusively
+        conn
+        (fn []
+          (a/>!! eval-chan code)
+          (prepl/await-ret conn))))))
 
 ;; The following functions are called by the user through commands.
 
-(defn eval* [{:keys [code line]}]
+(defn toggle-dry-run []
+  (if (swap! dry-run?! not)
+    (ui/info "Dry run enabled, code will be logged instead of evaluated")
+    (ui/info "Dry run disabled")))
+
+(defn toggle-reflection
+  "Flip *warn-on-reflection* and *unchecked-math* (warning on boxed math) in
+  the :bindings of the tagged connection, or every connection for the current
+  buffer."
+  [tag]
+  (let [conns (if tag
+                (filter #(= (:tag %) tag) (prepl/conns))
+                (:conns (current-ctx)))]
+    (doseq [{:keys [tag bindings]} conns]
+      (prepl/set-bindings! tag (if (get bindings '*warn-on-reflection*)
+                                 (dissoc bindings '*warn-on-reflection* '*unchecked-math*)
+                                 (assoc bindings
+                                        '*warn-on-reflection* true
+                                        '*unchecked-math* :warn-on-boxed))))))
+
+(defn- rename-key [m from to]
+  (if (contains? m from)
+    (-> m (dissoc from) (assoc to (get m from)))
+    m))
+
+(defn rename
+  "Move a connection to a new tag along with everything we remember about it,
+  so eval-last, retest-failed and friends carry on working."
+  [from to]
+  (when (prepl/rename! from to)
+    (doseq [state! [last-evals! last-failed! last-results! last-errors!]]
+      (swap! state! rename-key from to))
+    (swap! required! (fn [required]
+                       (into #{}
+                             (map (fn [[tag & more :as k]]
+                                    (if (= tag from)
+                                      (into [to] more)
+                                      k)))
+                             required)))))
+
+(defn auto-add []
+  (let [{:keys [path]} (nvim/current-ctx)
+        dir (if (str/blank? path) (nvim/cwd) path)
+        new-conns (prepl/discover dir)]
+    (if (empty? new-conns)
+      (ui/error "No port files found above" dir)
+      (doseq [new-conn new-conns]
+        (prepl/add! new-conn)))))
+
+(defn route [path]
+  (prepl/show-route (if (str/blank? path)
+                      (:path (nvim/current-ctx))
+                      path)))
+
+(defn interrupt
+  "Interrupt the given connection or every one for the current buffer."
+  [tag]
+  (if tag
+    (prepl/interrupt! tag)
+    (doseq [{:keys [tag]} (:conns (current-ctx))]
+      (prepl/interrupt! tag))))
+
+(defn in-ns*
+  "Switch the namespace evals fall back to, for buffers without an ns form, on
+  every connection for the current buffer. Defaults to the buffer's namespace."
+  [ns]
+  (let [ctx (current-ctx)]
+    (if-let [ns (or ns (:ns ctx))]
+      (doseq [{:keys [tag]} (:conns ctx)]
+        (prepl/set-ns! tag ns))
+      (ui/error "No namespace given and the buffer doesn't have one"))))
+
+(defn- store-result!
+  "Remember a successful result for yank-result and put it into
+  g:conjure_result_register if there is one."
+  [conn register {[status value] :val}]
+  (when (and (= status :error) (map? value) (:trace value))
+    (swap! last-errors! assoc (:tag conn) value))
+  (when (= status :ok)
+    (swap! last-results! assoc (:tag conn) value)
+    (when register
+      (nvim/set-register register (util/pprint value)))))
+
+(defn stack-trace
+  "Put the frames of the last error's stack trace that we can find the source
+  for into the quickfix list, from the tagged connection or the first Clojure
+  connection for the current buffer with an error."
+  [tag]
+  (let [ctx (current-ctx (cond-> {} tag (assoc :tag tag)))
+        conn (first (filter #(and (= (:lang %) :clj) (contains? @last-errors! (:tag %)))
+                            (:conns ctx)))]
+    (if-not conn
+      (ui/error "No stack trace to look at")
+      (let [{:keys [trace]} (get @last-errors! (:tag conn))
+            [status frames] (:val (wrapped-eval ctx {:conn conn
+                                                     :code (code/stack-frames-str {:trace trace})}))]
+        (cond
+          (not= status :ok) (ui/error "Couldn't find the stack trace sources on" (:tag conn))
+          (empty? frames) (ui/info "None of the stack trace comes from source Conjure can find")
+          :else (nvim/set-quickfix (str "Stack trace from " (name (:tag conn)))
+                                   (for [frame frames]
+                                     (update frame :filename #(or (util/extract-zipfile %) %)))))))))
+
+(def ^:private default-yank-register "+")
+
+(defn yank-result
+  "Copy the last successful result in full into a register, the system
+  clipboard by default. It's the result from the tagged connection or the first
+  connection for the current buffer that has one."
+  [{:keys [tag register]}]
+  (let [tags (if tag
+               [tag]
+               (map :tag (:conns (current-ctx))))
+        register (or register default-yank-register)]
+    (if-let [tag (first (filter #(contains? @last-results! %) tags))]
+      (do
+        (nvim/set-register register (util/pprint (get @last-results! tag)))
+        (ui/info "Yanked the last result from" tag "into" (str "\"" register)))
+      (ui/error "No results to yank yet"))))
+
+(defn expand-elision
+  "Fetch the first part of the last result that unrepl elided, from the tagged
+  connection or the first unrepl connection for the current buffer with one,
+  and display the result again with it spliced back in. Expanding again fetches
+  the next elision."
+  [tag]
+  (let [ctx (current-ctx (cond-> {} tag (assoc :tag tag)))
+        conn (first (filter #(and (= (:protocol %) :unrepl)
+                                  (unrepl/first-elision (get @last-results! (:tag %))))
+                            (:conns ctx)))]
+    (if-not conn
+      (ui/error "No elided results to expand")
+      (let [value (get @last-results! (:tag conn))
+            elision (unrepl/first-elision value)
+            resp (raw-eval ctx {:conn conn
+                                :code (pr-str (get-in elision [:form :get]))})
+            expansion (:val resp)]
+        (cond
+          (not= (:tag resp) :ret)
+          (ui/error "Couldn't fetch the elided value from" (:tag conn))
+
+          (and (vector? expansion) (= (first expansion) :error))
+          (ui/result {:conn conn, :resp resp})
+
+          :else
+          (let [value (unrepl/splice value elision expansion)]
+            (swap! last-results! assoc (:tag conn) value)
+            (ui/result {:conn conn, :resp {:tag :ret, :val [:ok value]}})))))))
+
+(defn stdin
+  "Send a line of input to every busy connection for the current buffer, or
+  just the given tag."
+  [{:keys [tag text]}]
+  (if tag
+    (prepl/stdin! tag text)
+    (doseq [{:keys [tag ^java.util.concurrent.locks.ReentrantLock lock]} (:conns (current-ctx))]
+      (when (and lock (.isLocked lock))
+        (prepl/stdin! tag text)))))
+
+(defn- ensure-required!
+  "Require the buffer's namespace the first time we evaluate something from it
+  on a connection, so a single form from a file we haven't loaded yet can find
+  everything it refers to. Only done once per connection (and reconnection)."
+  [ctx conn {:keys [auto-require auto-require-reload]}]
+  (let [{:keys [ns]} ctx
+        k [(:tag conn) (:connected-at conn) ns]]
+    (when (and ns (= auto-require 1) (not (contains? @required! k)))
+      (swap! required! conj k)
+      (let [[status] (:val (wrapped-eval ctx {:conn conn
+                                              :code (code/require-str
+                                                      {:ns ns
+                                                       :reload? (= auto-require-reload 1)})}))]
+        (when-not (= status :ok)
+          (log/warn "Couldn't require" ns "on" (:tag conn)))))))
+
+(defn eval*
+  "Evaluate some code in every matching connection, or just the one under :tag.
+  The namespace comes from the buffer unless :ns is given."
+  [{:keys [code line column tag ns]}]
   (when code
+    (if-let [problem (code/unbalanced code)]
+      (ui/error "Not evaluating unbalanced code:" problem)
+      (let [ctx (cond-> (current-ctx {:tag tag})
+                  ns (assoc :ns ns))
+            {:keys [result-register slow-eval-threshold log-timestamps virtual-text hud hud-timeout]
+             :as config}
+            (nvim/configs {:result-register nil
+                           :virtual-text 0
+                           :hud 0
+                           :hud-timeout default-hud-timeout
+                           :slow-eval-threshold default-slow-eval-threshold
+                           :log-timestamps 1
+                           :auto-require 1
+                           :auto-require-reload 0})]
+        (doseq [conn (:conns ctx)]
+          (ensure-required! ctx conn config)
+          (let [opts {:conn conn, :code code, :line line, :column column, :stream? true}
+                _ (ui/eval* (assoc opts
+                                   :ns (eval-ns ctx conn)
+                                   :timestamp? (= log-timestamps 1)))
+                _ (swap! last-evals! assoc (:tag conn) (select-keys (assoc opts :ns (:ns ctx))
+                                                                    [:code :line :column :ns]))
+                start (System/currentTimeMillis)
+                resp (wrapped-eval ctx opts)
+                elapsed (- (System/currentTimeMillis) start)]
+            (when-not @dry-run?!
+              (prepl/count-eval! conn))
+            (ui/result {:conn conn
+                        :resp resp
+                        :elapsed (when (= log-timestamps 1) elapsed)})
+            (store-result! conn result-register resp)
+
+            (when (and (= hud 1) (= (:tag resp) :ret))
+              (ui/hud {:conn conn, :resp resp, :timeout hud-timeout}))
+
+            ;; Results go next to the last line of code they came from too.
+            (when (and (= virtual-text 1) line (= (:tag resp) :ret))
+              (ui/virtual-text {:buf (:buf ctx)
+                                :line (+ line (count (re-seq #"\n" (str/trim-newline code))))
+                                :resp resp}))
+
+            ;; You've probably wandered off if it took this long, let you know
+            ;; it's done wherever you are.
+            (when (and (pos? slow-eval-threshold) (> elapsed slow-eval-threshold))
+              (nvim/notify (str "Conjure: " (name (:tag conn)) " finished "
+                                (code/sample code) " in " (util/duration-str elapsed))))))))))
+
+(defn eval-last
+  "Evaluate the last thing we evaluated on the connection again, in the same
+  namespace. Without a tag it's every connection for the current buffer."
+  [tag]
+  (let [tags (if tag
+               [tag]
+               (map :tag (:conns (current-ctx))))]
+    (doseq [tag tags]
+      (if-let [last-eval (get @last-evals! tag)]
+        (eval* (assoc last-eval :tag tag))
+        (ui/error "Nothing has been evaluated on" tag "yet")))))
+
+(defn macroexpand*
+  "Display the expansion of the code, or the form under the cursor, for the
+  given depth (see code/macroexpand-str)."
+  [{:keys [code depth]}]
+  (when-let [code (if (str/blank? code)
+                    (:form (nvim/read-form))
+                    code)]
     (let [ctx (current-ctx)]
       (doseq [conn (:conns ctx)]
-        (let [opts {:conn conn, :code code, :line line}]
-          (ui/eval* opts)
+        (let [opts {:conn conn
+                    :code (code/macroexpand-str {:conn conn, :code code, :depth depth})}]
+          (ui/eval* (assoc opts :code code))
           (ui/result {:conn conn, :resp (wrapped-eval ctx opts)}))))))
 
-(defn doc [name]
+(defn inspect [name]
   (let [ctx (current-ctx)]
     (doseq [conn (:conns ctx)]
-      (let [code (code/doc-str {:conn conn, :name name})
+      (ui/eval* {:conn conn, :code name})
+      (ui/result {:conn conn
+                  :resp (wrapped-eval ctx {:conn conn
+                                           :code (code/inspect-str {:conn conn, :name name})})}))))
+
+(defn doc
+  "Display the docs for the name in the log, or a floating window if
+  g:conjure_doc_display is \"float\". Only the first connection with any docs
+  is used for the floating window."
+  [name]
+  (let [ctx (current-ctx)
+        name (code/resolve-alias ctx name)
+        float? (= (nvim/config :doc-display "log") "float")
+        results (for [conn (:conns ctx)]
+                  (let [code (code/doc-str {:conn conn, :name name})]
+                    {:conn conn
+                     :resp (-> (wrapped-eval ctx {:conn conn, :code code})
+                               (update :val second))}))
+        missing? (comp empty? :val :resp)
+        no-doc #(assoc-in % [:resp :val] (str "No doc for " name))]
+    (if float?
+      (when (seq results)
+        (let [{:keys [resp]} (or (first (remove missing? results))
+                                 (no-doc (first results)))]
+          (ui/floating (str/trim (:val resp)))))
+      (doseq [result results]
+        (ui/doc (cond-> result (missing? result) (no-doc)))))))
+
+(defn examples
+  "The docs for the name followed by its examples from the ClojureDocs export
+  at g:conjure_clojuredocs_export."
+  [name]
+  (let [export (nvim/config :clojuredocs-export)]
+    (if-not (and export (.exists (io/file export)))
+      (ui/error "Examples need a ClojureDocs export, download"
+                "https://clojuredocs.org/clojuredocs-export.json"
+                "and point g:conjure_clojuredocs_export at it")
+      (let [ctx (current-ctx)
+            name (code/resolve-alias ctx name)]
+        (doc name)
+        (doseq [conn (:conns ctx)]
+          (let [[status var-name] (:val (wrapped-eval ctx {:conn conn
+                                                           :code (code/qualified-name-str {:name name})}))
+                found (when (and (= status :ok) (string? var-name))
+                        (clojuredocs/lookup export var-name))]
+            (if found
+              (ui/examples {:conn conn, :msg (clojuredocs/render var-name found)})
+              (ui/doc {:conn conn, :resp {:val (str "No examples for " name)}}))))))))
+
+(defn source [name]
+  (let [ctx (current-ctx)
+        name (code/resolve-alias ctx name)]
+    (doseq [conn (:conns ctx)]
+      (let [code (code/source-str {:conn conn, :name name})
             result (-> (wrapped-eval ctx {:conn conn, :code code})
                        (update :val second))]
+        (if (str/blank? (:val result))
+          (ui/doc {:conn conn, :resp (assoc result :val (str "No source for " name))})
+          (ui/source {:conn conn, :resp result}))))))
+
+(defn spec [name]
+  (let [ctx (current-ctx)
+        name (code/resolve-alias ctx name)]
+    (doseq [conn (:conns ctx)]
+      (let [resp (wrapped-eval ctx {:conn conn
+                                    :code (code/spec-str {:conn conn, :name name})})]
+        (if (= (:val resp) [:ok nil])
+          (ui/doc {:conn conn, :resp {:val (str "No spec for " name)}})
+          (ui/result {:conn conn, :resp resp}))))))
+
+(defn ns-publics*
+  "List the public vars of the namespace, the current buffer's by default."
+  [ns]
+  (let [ctx (current-ctx)
+        ns (or ns (:ns ctx))]
+    (if-not ns
+      (ui/error "No namespace given and the buffer doesn't have one")
+      (doseq [conn (:conns ctx)]
+        (let [[status lines] (:val (wrapped-eval ctx {:conn conn
+                                                      :code (code/ns-publics-str {:conn conn, :ns ns})}))]
+          (ui/doc {:conn conn
+                   :resp {:val (cond
+                                 (not= status :ok) (str "Couldn't list the publics of " ns)
+                                 (nil? lines) (str ns " isn't loaded")
+                                 (empty? lines) (str ns " doesn't have any public vars")
+                                 :else (util/join-lines lines))}}))))))
+
+(defn apropos [pattern]
+  (let [ctx (current-ctx)]
+    (doseq [conn (:conns ctx)]
+      (let [[status matches] (:val (wrapped-eval ctx {:conn conn
+                                                      :code (code/apropos-str {:conn conn
+                                                                               :pattern pattern})}))]
         (ui/doc {:conn conn
-                 :resp (cond-> result
-                         (empty? (:val result))
-                         (assoc :val (str "No doc for " name)))})))))
+                 :resp {:val (cond
+                               (not= status :ok) (str "Couldn't search for " pattern)
+                               (empty? matches) (str "Nothing matches " pattern)
+                               :else (util/join-lines matches))}})))))
 
 (defn eval-current-form []
   (let [{:keys [form origin]} (nvim/read-form)]
     (eval* {:code form
-            :line (first origin)})))
+            :line (first origin)
+            :column (second origin)})))
 
 (defn eval-root-form []
   (let [{:keys [form origin]} (nvim/read-form {:root? true})]
     (eval* {:code form
-            :line (first origin)})))
+            :line (first origin)
+            :column (second origin)})))
+
+(defn eval-selection
+  "Evaluate each top level form in the selection one after the other, so every
+  one gets its own result and a broken form doesn't take the rest with it."
+  []
+  (let [{:keys [selection origin]} (nvim/read-selection)
+        [row col] origin]
+    (doseq [{:keys [code line column]} (code/split-forms selection)]
+      (eval* {:code code
+              :line (+ row (dec line))
+              :column (if (= line 1)
+                        (+ col (dec column))
+                        column)}))))
+
+(defn- eval-write-back
+  "Evaluate the form under the cursor on the first matching connection and hand
+  the form along with the pretty printed result to write!. Errors are logged
+  and leave the buffer alone."
+  [write!]
+  (when-let [{:keys [form origin] :as read} (nvim/read-form)]
+    (let [ctx (current-ctx)]
+      (when-let [conn (first (:conns ctx))]
+        (let [opts {:conn conn, :code form, :line (first origin), :column (second origin), :stream? true}
+              _ (ui/eval* (assoc opts :ns (eval-ns ctx conn)))
+              resp (wrapped-eval ctx opts)
+              [status value] (:val resp)]
+          (if (= status :ok)
+            (write! read (util/pprint value))
+            (ui/result {:conn conn, :resp resp})))))))
+
+(defn eval-replace
+  "Evaluate the form under the cursor and replace it with the result. Only the
+  first matching connection is used, errors are logged and leave the buffer
+  alone."
+  []
+  (eval-write-back nvim/replace-form))
 
-(defn eval-selection []
-  (let [{:keys [selection origin]} (nvim/read-selection)]
-    (eval* {:code selection
-            :line (first origin)})))
+(defn eval-comment
+  "Evaluate the form under the cursor and insert the result as a comment below
+  it. Like eval-replace only the first matching connection is used."
+  []
+  (eval-write-back
+    (fn [read result]
+      (nvim/insert-after-form read (code/result-comment result)))))
 
 (defn eval-buffer []
   (eval* {:code (nvim/read-buffer)}))
 
-(defn load-file* [path]
+(defn- dev-tag
+  "The connection dev commands go to, the given tag or g:conjure_dev_setup_tag.
+  Nil means every connection for the current buffer."
+  [tag]
+  (or tag
+      (let [dev-setup-tag (nvim/config :dev-setup-tag)]
+        (when-not (str/blank? dev-setup-tag)
+          (keyword (str/replace dev-setup-tag #"^:" ""))))))
+
+(defn custom
+  "Evaluate one of the code templates from g:conjure_custom_commands.
+  {{ns}}, {{word}}, {{form}}, {{root-form}}, {{selection}}, {{path}} and
+  {{args}} are replaced with what they say before it's sent."
+  [name args word]
+  (if-let [template (get (nvim/config :custom-commands {}) name)]
+    (let [ctx (nvim/current-ctx)
+          code (util/render-template
+                 template
+                 (fn [k]
+                   (case k
+                     :ns (:ns ctx)
+                     :path (:path ctx)
+                     :word word
+                     :args args
+                     :form (:form (nvim/read-form))
+                     :root-form (:form (nvim/read-form {:root? true}))
+                     :selection (:selection (nvim/read-selection))
+                     (do
+                       (ui/error "Unknown placeholder in" name "-" k)
+                       ""))))]
+      (eval* {:code code}))
+    (ui/error "No custom command named" name "in g:conjure_custom_commands")))
+
+(def ^:private tasks-file-name ".conjure-tasks.edn")
+
+(defn- load-tasks
+  "The tasks from the closest .conjure-tasks.edn above the current buffer (or
+  the working directory), a map of task names to {:code, :tag, :ns} maps.
+  Returns nil after displaying an error if there isn't a usable file."
+  []
+  (let [{:keys [path]} (nvim/current-ctx)
+        file (util/find-up (if (str/blank? path) (nvim/cwd) path) tasks-file-name)]
+    (if-not file
+      (ui/error "No" tasks-file-name "found above" (or path (nvim/cwd)))
+      (let [tasks (try
+                    (edn/read-string {:readers {'regex re-pattern}} (slurp file))
+                    (catch Exception e
+                      (log/warn "Couldn't read tasks from" (str file) e)))]
+        (if (and (map? tasks) (every? (comp string? :code) (vals tasks)))
+          tasks
+          (ui/error (str file) "should be a map of task names to maps with a :code string"))))))
+
+(defn tasks
+  "List the project's tasks."
+  []
+  (when-let [tasks (load-tasks)]
+    (ui/info (str "Tasks:\n"
+                  (->> tasks
+                       (sort-by (comp str key))
+                       (map (fn [[task {:keys [code tag]}]]
+                              (str "  " (name task) (when tag (str " " tag)) " - " (code/sample code))))
+                       (util/join-lines))))))
+
+(defn run-task
+  "Evaluate the project's task, on its :tag and in its :ns if it has them."
+  [task]
+  (when-let [tasks (load-tasks)]
+    (if-let [{:keys [code tag ns]} (get tasks (keyword task))]
+      (eval* {:code code, :tag tag, :ns ns})
+      (ui/error "No task named" task "in" tasks-file-name))))
+
+(defn dev-setup
+  "Bring up your dev environment by evaluating g:conjure_dev_setup, or loading
+  g:conjure_dev_setup_file, in the user namespace. It goes to the given tag,
+  then g:conjure_dev_setup_tag, then every connection for the current buffer."
+  [tag]
+  (let [{:keys [dev-setup dev-setup-file]} (nvim/configs {:dev-setup nil
+                                                          :dev-setup-file nil})
+        tag (dev-tag tag)]
+    (cond
+      (not (str/blank? dev-setup-file))
+      (eval* {:code (code/load-file-str dev-setup-file), :tag tag, :ns 'user})
+
+      (not (str/blank? dev-setup))
+      (eval* {:code dev-setup, :tag tag, :ns 'user})
+
+      :else
+      (ui/error "Set g:conjure_dev_setup or g:conjure_dev_setup_file to use ConjureDevSetup"))))
+
+(def ^:private default-system-fns
+  {:start "(go)"
+   :reset "(reset)"
+   :stop "(halt)"})
+
+(defn system
+  "Start, reset or stop your reloaded workflow system by evaluating
+  g:conjure_system_start, g:conjure_system_reset or g:conjure_system_stop in
+  the user namespace, which default to integrant.repl's fns. Goes to the same
+  connection as dev-setup."
+  [op tag]
+  (eval* {:code (nvim/config (keyword (str "system-" (name op))) (get default-system-fns op))
+          :tag (dev-tag tag)
+          :ns 'user}))
+
+(defn add-lib
+  "Pull a new library into each Clojure connection for the current buffer."
+  [lib coord]
+  (let [ctx (current-ctx)
+        code (code/add-lib-str {:lib lib, :coord coord})]
+    (doseq [conn (:conns ctx)]
+      (if (= (:lang conn) :clj)
+        (ui/result {:conn conn, :resp (wrapped-eval ctx {:conn conn, :code code})})
+        (ui/error "Can't add libraries to" (:tag conn))))))
+
+(defn unmap
+  "Remove the mappings for the names from the current buffer's namespace so
+  old definitions can't hang around."
+  [names]
+  (let [ctx (current-ctx)
+        ns (:ns ctx)]
+    (if-not ns
+      (ui/error "The buffer doesn't have a namespace to unmap from")
+      (doseq [conn (:conns ctx)]
+        (let [resp (wrapped-eval ctx {:conn conn, :code (code/unmap-str {:ns ns, :names names})})]
+          (if (= (first (:val resp)) :ok)
+            (ui/doc {:conn conn, :resp {:val (str "Unmapped " (str/join ", " names) " from " ns)}})
+            (ui/result {:conn conn, :resp resp})))))))
+
+(defn unmap-stale
+  "Unmap every var in the current buffer's namespace that the buffer no longer
+  mentions, such as the old name after a rename. The arrow constructors that
+  come with records and types are left alone."
+  []
+  (let [ctx (current-ctx)
+        ns (:ns ctx)
+        words (set (re-seq #"[^\s()\[\]{}\"',`;@^~]+" (nvim/read-buffer)))]
+    (if-not ns
+      (ui/error "The buffer doesn't have a namespace to unmap from")
+      (doseq [conn (:conns ctx)]
+        (if-not (= (:lang conn) :clj)
+          (ui/error "Can't find stale vars on" (:tag conn))
+          (let [[status interns] (:val (wrapped-eval ctx {:conn conn, :code (code/ns-interns-str {:ns ns})}))
+                stale (when (= status :ok)
+                        (->> interns
+                             (remove words)
+                             (remove #(re-find #"^(map)?->" %))
+                             (sort)))]
+            (cond
+              (not= status :ok) (ui/error "Couldn't list the vars in" ns "on" (:tag conn))
+              (empty? stale) (ui/doc {:conn conn, :resp {:val (str "No stale vars in " ns)}})
+              :else (let [resp (wrapped-eval ctx {:conn conn, :code (code/unmap-str {:ns ns, :names stale})})]
+                      (if (= (first (:val resp)) :ok)
+                        (ui/doc {:conn conn, :resp {:val (str "Unmapped stale " (str/join ", " stale) " from " ns)}})
+                        (ui/result {:conn conn, :resp resp}))))))))))
+
+(defn unalias
+  "Remove an alias from the current buffer's namespace."
+  [alias]
+  (let [ctx (current-ctx)
+        ns (:ns ctx)]
+    (if-not ns
+      (ui/error "The buffer doesn't have a namespace to unalias from")
+      (doseq [conn (:conns ctx)]
+        (let [resp (wrapped-eval ctx {:conn conn, :code (code/unalias-str {:ns ns, :alias alias})})]
+          (if (= (first (:val resp)) :ok)
+            (ui/doc {:conn conn, :resp {:val (str "Removed the " alias " alias from " ns)}})
+            (ui/result {:conn conn, :resp resp})))))))
+
+(defn unused-requires
+  "List the aliases and refers the current buffer's namespace doesn't use. The
+  REPL reads the file from disk, so save first."
+  []
+  (let [ctx (current-ctx)
+        ns (:ns ctx)]
+    (if-not (and ns (:path ctx))
+      (ui/error "The buffer needs a namespace and a file to check")
+      (doseq [conn (:conns ctx)]
+        (if-not (= (:lang conn) :clj)
+          (ui/error "Can't find unused requires on" (:tag conn))
+          (let [resp (wrapped-eval ctx {:conn conn
+                                        :code (code/unused-requires-str {:ns ns, :path (:path ctx)})})
+                [status {:keys [aliases refers]}] (:val resp)]
+            (if-not (= status :ok)
+              (ui/result {:conn conn, :resp resp})
+              (ui/doc {:conn conn
+                       :resp {:val (if (and (empty? aliases) (empty? refers))
+                                     (str "Everything " ns " requires is used")
+                                     (str/join "\n" (cond-> []
+                                                      (seq aliases) (conj (str "Unused aliases: " (str/join ", " aliases)))
+                                                      (seq refers) (conj (str "Unused refers: " (str/join ", " refers))))))}}))))))))
+
+(defn classpath
+  "Display each Clojure connection's classpath, only the entries containing
+  the query if there is one."
+  [query]
+  (let [ctx (current-ctx)
+        code (code/classpath-str {:query query})]
+    (doseq [conn (:conns ctx)]
+      (if (= (:lang conn) :clj)
+        (let [resp (wrapped-eval ctx {:conn conn, :code code})]
+          (if (= (first (:val resp)) :ok)
+            (ui/classpath {:conn conn, :resp (update resp :val second)})
+            (ui/result {:conn conn, :resp resp})))
+        (ui/error "Can't list the classpath of" (:tag conn))))))
+
+(defn load-file*
+  "Ask the REPL to load the file from disk, only the path is sent so the file
+  and line metadata are correct. Defaults to the current buffer's file."
+  [path]
   (let [ctx (current-ctx)
+        path (if (str/blank? path) (:path ctx) path)
         code (code/load-file-str path)]
     (doseq [conn (:conns ctx)]
       (let [opts {:conn conn, :code code, :path path}]
         (ui/load-file* opts)
         (ui/result {:conn conn, :resp (raw-eval ctx opts)})))))
 
-(defn- completion-context [prefix]
+(defn- completion-context
+  "The root form around the cursor with the prefix swapped for __prefix__, for
+  Compliment, along with how often each word appears in the form so words
+  you're already using nearby rank higher."
+  [prefix]
   (when-let [{:keys [form cursor]} (nvim/read-form {:root? true})]
-    (-> (util/split-lines form)
-        (update (dec (first cursor))
-                #(util/splice %
-                              (- (second cursor) (count prefix))
-                              (second cursor)
-                              "__prefix__"))
-        (util/join-lines))))
-
-(defn completions [prefix]
+    {:context (-> (util/split-lines form)
+                  (update (dec (first cursor))
+                          #(util/splice %
+                                        (- (second cursor) (count prefix))
+                                        (second cursor)
+                                        "__prefix__"))
+                  (util/join-lines))
+     :used (frequencies (re-seq #"[^\s()\[\]{}\"'`,;@~^]+" form))}))
+
+(def ^:private default-completion-limit 50)
+
+(defn completions
+  "Completions from every connection for the current buffer, ranked here so
+  only the best g:conjure_completion_limit of them go back to Neovim. Words
+  you've already used in the form around the cursor are ranked higher."
+  [prefix]
   (let [ctx (current-ctx {:silent? true})
-        context (completion-context prefix)]
+        {:keys [context used]} (completion-context prefix)
+        limit (nvim/config :completion-limit default-completion-limit)]
     (->> (:conns ctx)
          (mapcat
            (fn [conn]
@@ -117,16 +759,62 @@
                    (get :val)
                    (second)
                    (->> (map
-                          (fn [{:keys [candidate type ns package]}]
+                          (fn [{:keys [candidate type ns package arglists]}]
                             (let [menu (or ns package)]
-                              (util/kw->snake-map
-                                (cond-> {:word candidate
-                                         :kind (subs (name type) 0 1)}
-                                  menu (assoc :menu menu)))))))))))
-         (dedupe))))
+                              (cond-> {:word candidate
+                                       :kind (subs (name type) 0 1)}
+                                menu (assoc :menu menu)
+                                (seq arglists) (assoc :info (str candidate " " (str/join " " arglists))))))))))))
+         (distinct)
+         (#(util/fuzzy-rank {:query prefix
+                             :items %
+                             :word-fn :word
+                             :frequencies used
+                             :limit limit}))
+         (map util/kw->snake-map))))
+
+(defn arglists
+  "A one line signature for the name from the first connection that knows it,
+  nil if none of them do."
+  [name]
+  (when-not (str/blank? name)
+    (let [ctx (current-ctx {:silent? true})
+          full-name (code/resolve-alias ctx name)]
+      (some
+        (fn [conn]
+          (let [[status arglists] (:val (wrapped-eval ctx {:conn conn
+                                                           :code (code/arglists-str {:name full-name})}))]
+            (when (and (= status :ok) (string? arglists))
+              (str name " " arglists))))
+        (:conns ctx)))))
+
+(defn usages
+  "Put every form in the loaded namespaces that refers to the name into the
+  quickfix list."
+  [name]
+  (let [ctx (current-ctx)
+        name (code/resolve-alias ctx name)
+        entries (mapcat
+                  (fn [conn]
+                    (let [[status found] (:val (wrapped-eval ctx {:conn conn
+                                                                  :code (code/usages-str {:name name})}))]
+                      (when (= status :ok)
+                        (for [[file line text] found]
+                          {:filename file, :lnum line, :col 1, :text text}))))
+                  (:conns ctx))]
+    (if (empty? entries)
+      (ui/error "No usages of" name "found in the loaded namespaces")
+      (nvim/set-quickfix (str "Usages of " name) (distinct entries)))))
+
+(defn document-symbols
+  "The top level definitions of the current buffer for outline plugins, no
+  connection required."
+  []
+  (map util/kw->snake-map (code/document-symbols (nvim/read-buffer))))
 
 (defn definition [name]
   (let [ctx (current-ctx)
+        name (code/resolve-alias ctx name)
         lookup (fn [conn]
                  (-> (wrapped-eval ctx
                                    {:conn conn
@@ -136,32 +824,125 @@
                      (second)))
         coord (some lookup (:conns ctx))]
     (if (vector? coord)
-      (nvim/edit-at ctx coord)
+      (nvim/edit-at ctx (update coord 0 #(or (util/extract-zipfile %) %)))
       (do
         (log/warn "Non-vector definition result:" coord)
         (nvim/definition)))))
 
-(defn run-tests [targets]
+(defn- test!
+  "Run some test code on the connection and display the report. The failed
+  tests are remembered for retest-failed and put into the quickfix list, the
+  first failing assertion is remembered for jump-to-failure."
+  ([ctx conn code] (test! ctx conn code {}))
+  ([ctx conn code {:keys [quiet?]}]
+   (let [resp (-> (wrapped-eval ctx {:conn conn, :code code})
+                  (update :val second))
+         {:keys [failed failures results]} (when (map? (:val resp)) (:val resp))]
+     (when failed
+       (swap! last-failed! assoc (:tag conn) failed))
+     (when-let [failure (first (filter (every-pred :file :line) failures))]
+       (reset! last-failure! failure))
+     (ui/test* {:conn conn, :resp resp, :quiet? quiet?})
+     (when-let [results (seq (filter (every-pred :file :line) results))]
+       (nvim/place-test-signs results))
+     (when-let [entries (seq (for [{:keys [file line text]} failures
+                                   :when file]
+                               {:filename file, :lnum (or line 1), :col 1, :text text}))]
+       (nvim/set-quickfix (str "Test failures on " (name (:tag conn))) entries)))))
+
+(defn run-tests
+  ([targets] (run-tests targets {}))
+  ([targets opts]
+   (let [ctx (current-ctx)
+         ns (:ns ctx)
+         other-ns (if (str/ends-with? ns "-test")
+                    (str/replace ns #"-test$" "")
+                    (str ns "-test"))]
+     (doseq [conn (:conns ctx)]
+       (test! ctx conn (code/run-tests-str
+                         {:conn conn
+                          :targets (cond
+                                     (seq targets) targets
+                                     (= (:test-runner conn) :kaocha) #{(if (str/ends-with? ns "-test") ns other-ns)}
+                                     (= (:lang conn) :clj) #{ns other-ns}
+                                     :else #{ns})})
+              opts)))))
+
+(defn test-on-save
+  "Run the tests for the buffer that was just saved if g:conjure_test_on_save
+  is enabled, you'll only see the log if something failed."
+  []
+  (let [ctx (nvim/current-ctx)]
+    (when (and (:ns ctx) (seq (prepl/conns (:path ctx))))
+      (run-tests [] {:quiet? true}))))
+
+(defn run-current-test
+  "Evaluate the deftest under the cursor then run just that test."
+  []
+  (when-let [{:keys [form origin]} (nvim/read-form {:root? true})]
+    (let [{:keys [name kind]} (first (code/document-symbols form))]
+      (if-not (and kind (str/ends-with? kind "deftest"))
+        (ui/error "The cursor isn't inside a deftest")
+        (let [ctx (current-ctx)]
+          (doseq [conn (:conns ctx)]
+            (let [resp (wrapped-eval ctx {:conn conn
+                                          :code form
+                                          :line (first origin)
+                                          :column (second origin)})]
+              (if (= (first (:val resp)) :ok)
+                (test! ctx conn (code/run-test-str {:conn conn, :names [(str (:ns ctx) "/" name)]}))
+                (ui/result {:conn conn, :resp resp})))))))))
+
+(defn retest-failed
+  "Run only the tests that failed last time on each connection."
+  []
+  (let [ctx (current-ctx)]
+    (doseq [conn (:conns ctx)]
+      (if-let [failed (seq (get @last-failed! (:tag conn)))]
+        (test! ctx conn (code/run-test-str {:conn conn, :names failed}))
+        (ui/info "No failed tests to run again on" (:tag conn))))))
+
+(defn refresh
+  "Reload the changed namespaces on each Clojure connection with
+  tools.namespace, or every namespace when all? is set. When one fails to load
+  the error is put into the quickfix list too."
+  [all?]
+  (let [ctx (current-ctx)]
+    (doseq [conn (:conns ctx)]
+      (if-not (= (:lang conn) :clj)
+        (ui/error "Can't refresh" (:tag conn) "with tools.namespace")
+        (let [resp (wrapped-eval ctx {:conn conn, :code (code/refresh-str {:all? all?})})]
+          (if (= (first (:val resp)) :ok)
+            (let [resp (update resp :val second)
+                  {:keys [file line message]} (get-in resp [:val :error])]
+              (ui/refresh {:conn conn, :resp resp})
+              (when file
+                (nvim/set-quickfix (str "Refresh error on " (name (:tag conn)))
+                                   [{:filename file, :lnum (or line 1), :col 1, :text message}])))
+            (ui/result {:conn conn, :resp resp})))))))
+
+(defn jump-to-failure
+  "Move the cursor to the failing assertion from the last test run that had one."
+  []
+  (if-let [{:keys [file line]} @last-failure!]
+    (nvim/edit-at (nvim/current-ctx) [file line 0])
+    (ui/info "No test failures to jump to")))
+
+(def ^:private default-test-ns-pattern "-test$")
+
+(defn run-project-tests
+  "Discover and run every test namespace in the project on each Clojure
+  connection for the current buffer. The pattern defaults to
+  g:conjure_test_ns_pattern."
+  [pattern]
   (let [ctx (current-ctx)
-        ns (:ns ctx)
-        other-ns (if (str/ends-with? ns "-test")
-                   (str/replace ns #"-test$" "")
-                   (str ns "-test"))]
+        pattern (or pattern (nvim/config :test-ns-pattern default-test-ns-pattern))]
     (doseq [conn (:conns ctx)]
-      (let [code (code/run-tests-str
-                   {:conn conn
-                    :targets (if (empty? targets)
-                               (cond-> #{ns}
-                                 (= (:lang conn) :clj) (conj other-ns))
-                               targets)})]
-        (ui/test* {:conn conn
-                   :resp (-> (wrapped-eval ctx {:conn conn, :code code})
-                             (update :val second))})))))
+      (if (= (:lang conn) :clj)
+        (test! ctx conn (code/run-project-tests-str {:pattern pattern, :conn conn}))
+        (ui/error "Can't discover test namespaces on" (:tag conn) "yet, try ConjureRunAllTests")))))
 
 (defn run-all-tests [re]
   (let [ctx (current-ctx)]
     (doseq [conn (:conns ctx)]
-      (let [code (code/run-all-tests-str {:re re, :conn conn})]
-        (ui/test* {:conn conn
-                   :resp (-> (wrapped-eval ctx {:conn conn, :code code})
-                             (update :val second))})))))
+      (test! ctx conn (code/run-all-tests-str {:re re, :conn conn})))))
@@ -0,0 +1,41 @@
+(ns conjure.clojuredocs
+  "Examples and see alsos from an offline export of ClojureDocs."
+  (:require [clojure.java.io :as io]
+            [clojure.string :as str]
+            [clojure.core.memoize :as memo]
+            [cheshire.core :as json]
+            [conjure.util :as util]))
+
+(def ^:private load-export
+  "Read the export at the path and index the vars by their qualified name. The
+  export is pretty big so it's only read once per path."
+  (memo/lru
+    (fn [path]
+      (with-open [reader (io/reader path)]
+        (into {}
+              (map (juxt #(str (get % "ns") "/" (get % "name")) identity))
+              (get (json/parse-stream reader) "vars"))))))
+
+(defn lookup
+  "Examples and see alsos for the qualified var name, nil if the export doesn't
+  know about it."
+  [path var-name]
+  (when-let [entry (get (load-export path) var-name)]
+    {:examples (keep #(get % "body") (get entry "examples"))
+     :see-alsos (for [{{:strs [ns name]} "to-var"} (get entry "see-alsos")]
+                  (str ns "/" name))}))
+
+(defn render
+  "The examples as Clojure, separated by comments, so they're highlighted in
+  the log."
+  [var-name {:keys [examples see-alsos]}]
+  (util/join-lines
+    (concat
+      [(str ";; " (util/count-str examples "example") " for " var-name)]
+      (mapcat
+        (fn [n example]
+          [(str ";; Example " (inc n)) (str/trim example)])
+        (range)
+        examples)
+      (when (seq see-alsos)
+        [(str ";; See also: " (util/join-words see-alsos))]))))
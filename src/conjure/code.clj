@@ -2,7 +2,8 @@
   "Tools to render or format Clojure code."
   (:require [clojure.string :as str]
             [taoensso.timbre :as log]
-            [conjure.util :as util]))
+            [conjure.util :as util])
+  (:import [java.io PushbackReader StringReader]))
 
 (defn sample
   "Get a short one line sample snippet of some code."
@@ -13,6 +14,137 @@
         (str (subs flat 0 sample-length) "…")
         flat))))
 
+(defn result-comment
+  "A result as comment lines that can sit below the code that produced it."
+  [result]
+  (let [[first-line & lines] (util/split-lines result)]
+    (into [(str ";; => " first-line)]
+          (map #(str ";;    " %) lines))))
+
+(defn compiler-warnings
+  "The reflection and boxed math warnings in some stderr output as quickfix
+  entries."
+  [s]
+  (for [[_ kind file line col text] (re-seq #"(Reflection|Boxed math|Performance) warning, (.+?):(\d+):(\d+) - (.*)" s)]
+    {:filename file
+     :lnum (Long/parseLong line)
+     :col (Long/parseLong col)
+     :text (str kind ": " text)}))
+
+(def ^:private delimiters {\( \), \[ \], \{ \}})
+
+(defn unbalanced
+  "Check the delimiters in some code without reading it, strings, character
+  literals and comments are skipped. Returns a description of the first problem
+  or nil if everything is balanced. Sending unbalanced code to a prepl leaves it
+  waiting for the rest forever."
+  [code]
+  (loop [[c & more] (seq code)
+         state :code
+         line 1
+         stack ()]
+    (let [line (cond-> line (= c \newline) inc)]
+      (cond
+        (nil? c)
+        (cond
+          (= state :string) "Unterminated string"
+          (seq stack) (let [[open open-line] (first stack)]
+                        (str "Unclosed " open " from line " open-line))
+          :else nil)
+
+        (= state :string)
+        (case c
+          \\ (recur (rest more) :string (cond-> line (= (first more) \newline) inc) stack)
+          \" (recur more :code line stack)
+          (recur more :string line stack))
+
+        (= state :comment)
+        (recur more (if (= c \newline) :code :comment) line stack)
+
+        :else
+        (case c
+          \\ (recur (rest more) :code line stack)
+          \; (recur more :comment line stack)
+          \" (recur more :string line stack)
+          (\( \[ \{) (recur more :code line (conj stack [c line]))
+          (\) \] \}) (let [[open] (first stack)]
+                       (if (= (get delimiters open) c)
+                         (recur more :code line (rest stack))
+                         (str "Unexpected " c " on line " line
+                              (when open
+                                (str ", expected " (get delimiters open))))))
+          (recur more :code line stack))))))
+
+(defn- counting-reader
+  "A PushbackReader that keeps track of how many characters it's consumed in
+  the offset volatile, the reader only ever uses read and unread of a single
+  character."
+  [code offset]
+  (proxy [PushbackReader] [(StringReader. code)]
+    (read []
+      (let [c (proxy-super read)]
+        (when-not (= c -1)
+          (vswap! offset inc))
+        c))
+    (unread [c]
+      (vswap! offset dec)
+      (proxy-super unread c))))
+
+(defn- skip-whitespace
+  "Read past any whitespace, commas and comments."
+  [^PushbackReader rdr]
+  (loop []
+    (let [c (.read rdr)]
+      (cond
+        (= c -1) nil
+        (or (Character/isWhitespace (char c)) (= (char c) \,)) (recur)
+        (= (char c) \;) (do
+                          (loop []
+                            (let [c (.read rdr)]
+                              (when-not (or (= c -1) (= (char c) \newline))
+                                (recur))))
+                          (recur))
+        :else (.unread rdr c)))))
+
+(defn split-forms
+  "Split some code into its top level forms. Each one comes with the :line and
+  :column it starts at within the code. If the code can't be read here, such as
+  when it uses aliased keywords, it's returned as a single form."
+  [code]
+  (let [offset (volatile! 0)
+        rdr (counting-reader code offset)
+        eof (Object.)
+        position (fn [n]
+                   (let [before (subs code 0 n)]
+                     {:line (inc (count (filter #{\newline} before)))
+                      :column (- n (or (str/last-index-of before "\n") -1))}))]
+    (try
+      (binding [*default-data-reader-fn* tagged-literal
+                *read-eval* false]
+        (loop [forms []]
+          (skip-whitespace rdr)
+          (let [start @offset]
+            (if (identical? (read {:eof eof, :read-cond :preserve} rdr) eof)
+              forms
+              (recur (conj forms (merge {:code (subs code start @offset)}
+                                        (position start))))))))
+      (catch Exception e
+        (log/warn "Couldn't split code into forms" e)
+        [{:code code, :line 1, :column 1}]))))
+
+(defn document-symbols
+  "The top level definitions in some code as maps of :name, :kind (the def
+  form, such as defn or s/def) and :line. Top level forms almost always start
+  at the beginning of a line, so lines are matched instead of reading the code,
+  which would fail on things like aliased keywords."
+  [code]
+  (keep-indexed
+    (fn [n line]
+      (when-let [[_ kind name] (re-find #"^\(((?:[^\s/()]+/)?(?:ns|def\S*))\s+(?:\^(?:\{[^}]*\}|\S+)\s+)*([^\s()\[\]{}\"]+)"
+                                        line)]
+        {:name name, :kind kind, :line (inc n)}))
+    (util/split-lines code)))
+
 (defn parse-code [code]
   (binding [*default-data-reader-fn* tagged-literal]
     (read-string {:read-cond :preserve} code)))
@@ -25,44 +157,221 @@
     (catch Exception e
       (log/error "Caught error while extracting ns" e))))
 
-(defn prelude-str [{:keys [lang]}]
+(defn- libspec-aliases
+  "The aliases from a single libspec, prefix lists included."
+  [prefix spec]
+  (cond
+    (symbol? spec) nil
+    (and (sequential? spec) (symbol? (first spec)))
+    (let [[lib & more] spec
+          lib (if prefix (symbol (str prefix "." lib)) lib)]
+      (if (or (empty? more) (keyword? (first more)))
+        (let [opts (into {} (map vec) (partition 2 more))]
+          (keep (fn [k]
+                  (when-let [alias (get opts k)]
+                    [alias lib]))
+                [:as :as-alias]))
+        (mapcat #(libspec-aliases lib %) more)))))
+
+(defn parse-aliases
+  "A map of alias symbols to namespace symbols from the :require clauses of the
+  ns form at the start of the code. Reader conditionals are skipped."
+  [code]
+  (try
+    (let [form (parse-code code)]
+      (when (and (seq? form) (= (first form) 'ns))
+        (into {}
+              (for [clause form
+                    :when (and (seq? clause) (= (first clause) :require))
+                    spec (rest clause)
+                    alias (libspec-aliases nil spec)]
+                alias))))
+    (catch Exception e
+      (log/error "Caught error while extracting aliases" e))))
+
+(defn resolve-alias
+  "Expand the alias in a qualified symbol or keyword (including ::foo) using
+  the aliases and namespace of the buffer, anything else comes back as it was."
+  [{:keys [ns aliases]} name]
+  (if-let [[_ colons alias-str simple] (re-matches #"(::|:)?([^/:]+)/(.+)" name)]
+    (if-let [full (and (not= colons ":") (get aliases (symbol alias-str)))]
+      (str (when colons ":") full "/" simple)
+      name)
+    (if-let [[_ simple] (and ns (re-matches #"::([^/]+)" name))]
+      (str ":" ns "/" simple)
+      name)))
+
+(def bb-detect-str
+  "Returns the Babashka version when the REPL is Babashka, nil on the JVM."
+  "(System/getProperty \"babashka.version\")")
+
+(def thread-id-str
+  "The id of the thread the REPL evaluates our code on."
+  "(.getId (Thread/currentThread))")
+
+(defn interrupt-str
+  "Interrupt and then stop the thread with the given id. Thread.stop is what
+  actually breaks out of a loop, newer JVMs don't support it so we only get the
+  interrupt there."
+  [thread-id]
+  (str "
+       (when-let [thread (some #(when (= (.getId ^Thread %) " thread-id ") %)
+                               (keys (Thread/getAllStackTraces)))]
+         (.interrupt ^Thread thread)
+         (try
+           (.stop ^Thread thread)
+           (catch UnsupportedOperationException _))
+         true)
+       "))
+
+(defn heartbeat-str
+  "Something trivial for the heartbeat to evaluate. The REPL puts the result in
+  *1 and shifts the rest of the history along, so on the JVM the history is
+  stashed on *1's metadata first and restore-history-str puts it back before
+  the next eval. Back to back heartbeats leave the first stash alone."
+  [{:keys [lang bb? build]}]
+  (if (and (= lang :clj) (not bb?) (not build))
+    "(do
+       (when-not (= clojure.core/*1 :conjure/heartbeat)
+         (alter-meta! #'clojure.core/*1 assoc :conjure/history [clojure.core/*1 clojure.core/*2 clojure.core/*3]))
+       :conjure/heartbeat)\n"
+    ":conjure/heartbeat\n"))
+
+(def ^:private restore-history-str
+  "Undo what a heartbeat did to *1, *2 and *3, see heartbeat-str."
+  "(when (= clojure.core/*1 :conjure/heartbeat)
+     (let [[a b c] (:conjure/history (meta #'clojure.core/*1))]
+       (set! clojure.core/*1 a)
+       (set! clojure.core/*2 b)
+       (set! clojure.core/*3 c)))")
+
+(def tap-marker
+  "Prefixes tapped values printed to an nREPL session's output."
+  "\u0000conjure/tap ")
+
+(defn nrepl-tap-str
+  "nREPL doesn't send tap> values anywhere so we add a tap that prints them,
+  with a marker, to this session's output. It replaces the one from any earlier
+  connection under the same tag."
+  [{:keys [tag]}]
+  (let [handler (str "handler-" (name tag))]
+    (str "
+         (let [tap-ns (create-ns 'conjure.tap)
+               out *out*]
+           (when-let [old (some-> (ns-resolve tap-ns '" handler ") deref)]
+             (remove-tap old))
+           (add-tap
+             (intern tap-ns '" handler "
+                     (fn [value]
+                       (binding [*out* out]
+                         (print (str \"" (util/escape-quotes tap-marker) "\" (pr-str value) \"\\n\"))
+                         (flush)))))
+           nil)
+         ")))
+
+(defn prelude-str [{:keys [lang bb?]}]
   (case lang
-    :clj "(do
-            (require 'clojure.repl
-                     'clojure.string
-                     'clojure.java.io
-                     'clojure.test)
-            (try (require 'compliment.core) (catch Exception _)))"
+    :clj (if bb?
+           "(require 'clojure.repl 'clojure.string 'clojure.java.io 'clojure.test)"
+           "(do
+              (require 'clojure.repl
+                       'clojure.string
+                       'clojure.java.io
+                       'clojure.test)
+              (try (require 'compliment.core) (catch Exception _)))")
     :cljs "(require 'cljs.repl 'cljs.test)"))
 
+(defn- bindings-str
+  "The connection's :bindings as the inside of a binding vector."
+  [bindings]
+  (->> bindings
+       (map (fn [[sym value]] (str sym " " (pr-str value))))
+       (str/join " ")))
+
+(defn- with-bindings-str
+  "Wrap some code in the connection's :bindings if there are any."
+  [bindings code]
+  (if (seq bindings)
+    (str "(binding [" (bindings-str bindings) "] " code ")")
+    code))
+
+(defn- bb-eval-str
+  "Babashka doesn't have the Compiler or LineNumberingPushbackReader so we
+  lose line numbers, everything else matches the JVM version."
+  [{:keys [ns]} {:keys [conn code]}]
+  (str "
+       (try
+         (ns " (or ns "user") ")
+         [:ok " (with-bindings-str (:bindings conn)
+                  (str "(load-string \"(do " (util/escape-quotes code) "\n)\")")) "]
+         (catch Throwable e
+           (let [emap (Throwable->map e)]
+             (binding [*out* *err*]
+               (println (.getMessage e)))
+             [:error emap]))
+         (finally
+           (flush)))
+       "))
+
 ;; TODO Implement line offset for ClojureScript.
-(defn eval-str [{:keys [ns path]} {:keys [conn code line]}]
+;; The reader can't be told which column we start at so we pad the first line
+;; with spaces instead, the (do is on a line of its own to keep it out of the
+;; way.
+(def ^:private flush-interval 100)
+
+(defn- flusher-str
+  "Output is only sent once it's flushed, println does that for us but print
+  doesn't, so something flushes every so often while the body runs. Only worth
+  it for the user's own evals, our internal ones don't print anything."
+  [body]
+  (str "
+       (let [flusher (let [out *out*, err *err*]
+                       (future
+                         (while (not (Thread/interrupted))
+                           (Thread/sleep " flush-interval ")
+                           (.flush out)
+                           (.flush err))))]
+         (try
+           " body "
+           (finally
+             (future-cancel flusher))))
+       "))
+
+(defn eval-str
+  "Wrap code so it's evaluated in the right namespace at the right position and
+  returns [:ok value] or [:error emap]. Output is streamed while it runs if
+  :stream? is set."
+  [{:keys [ns path] :as ctx} {:keys [conn code line column stream?] :as opts}]
   (let [path-args-str (when-not (str/blank? path)
                         (str " \"" path "\" \"" (last (str/split path #"/")) "\""))]
     (case (:lang conn)
       :clj
-      (str "
-           (try
-             (ns " (or ns "user") ")
-             (let [rdr (-> (java.io.StringReader. \"(do " (util/escape-quotes code) "\n)\")
-                           (clojure.lang.LineNumberingPushbackReader.)
-                           (doto (.setLineNumber " (or line 1) ")))]
-               (binding [*default-data-reader-fn* tagged-literal]
-                 [:ok (. clojure.lang.Compiler (load rdr" path-args-str "))]))
-             (catch Throwable e
-               (let [emap (Throwable->map e)]
-                 (binding [*out* *err*]
-                   (println (-> emap clojure.main/ex-triage clojure.main/ex-str)))
-                 [:error emap]))
-             (finally
-               (flush)))
-           ")
+      (if (:bb? conn)
+        (bb-eval-str ctx opts)
+        (cond-> (str "
+             (try
+               " restore-history-str "
+               (ns " (or ns "user") ")
+               (let [rdr (-> (java.io.StringReader. \"(do\n" (str/join (repeat (dec (or column 1)) " ")) (util/escape-quotes code) "\n)\")
+                             (clojure.lang.LineNumberingPushbackReader.)
+                             (doto (.setLineNumber " (dec (or line 1)) ")))]
+                 (binding [*default-data-reader-fn* tagged-literal " (bindings-str (:bindings conn)) "]
+                   [:ok (. clojure.lang.Compiler (load rdr" path-args-str "))]))
+               (catch Throwable e
+                 (let [emap (Throwable->map e)]
+                   (binding [*out* *err*]
+                     (println (-> emap clojure.main/ex-triage clojure.main/ex-str)))
+                   [:error emap]))
+               (finally
+                 (flush)))
+             ")
+          stream? (flusher-str)))
 
       :cljs
       (str "
            (in-ns '" (or ns "cljs.user") ")
            (try
-             [:ok " code "]
+             [:ok " (with-bindings-str (:bindings conn) code) "]
              (catch :default e
                (let [emap (cljs.repl/Error->map e)]
                  (println (-> emap cljs.repl/ex-triage cljs.repl/ex-str))
@@ -71,26 +380,398 @@
                (flush)))
            "))))
 
+(defn shadow-eval-str
+  "Wrap ClojureScript code so it's evaluated in a shadow-cljs build from the
+  Clojure REPL shadow-cljs runs. The printed result is read back in with tagged
+  literals so JavaScript values like #js and #object survive the round trip."
+  [build code]
+  (str "
+       (do
+         (require 'shadow.cljs.devtools.api)
+         (let [{:keys [results out err]} (shadow.cljs.devtools.api/cljs-eval
+                                           " build "
+                                           \"" (util/escape-quotes code) "\"
+                                           {})]
+           (when (seq out)
+             (print out)
+             (flush))
+           (when (seq err)
+             (binding [*out* *err*]
+               (print err)
+               (flush)))
+           (when-let [result (last results)]
+             (try
+               (binding [*default-data-reader-fn* tagged-literal]
+                 (read-string result))
+               (catch Exception _
+                 result)))))
+       "))
+
+(defn- java-doc-str
+  "Describe a Java class or Class/member through reflection, with a link to the
+  Javadoc when clojure.java.javadoc knows where it lives. Returns nil for
+  anything that isn't a class."
+  [name]
+  (str "
+       (let [sym '" name "
+             [class-sym member] (if (namespace sym)
+                                  [(symbol (namespace sym)) (clojure.core/name sym)]
+                                  [sym nil])
+             c (try (resolve class-sym) (catch Exception _ nil))
+             signature (fn [m]
+                         (str (when (java.lang.reflect.Modifier/isStatic (.getModifiers m)) \"static \")
+                              (if (instance? java.lang.reflect.Method m)
+                                (str (.getSimpleName (.getReturnType m)) \" \" (.getName m))
+                                (.getSimpleName (.getDeclaringClass m)))
+                              \"(\" (clojure.string/join \", \" (map #(.getSimpleName %) (.getParameterTypes m))) \")\"))]
+         (when (class? c)
+           (require 'clojure.java.javadoc)
+           (with-out-str
+             (println \"-------------------------\")
+             (println (str (.getName c) (some->> member (str \"/\"))))
+             (when-let [url (try
+                              ((resolve 'clojure.java.javadoc/javadoc-url) (.getName c))
+                              (catch Exception _ nil))]
+               (println \" \" url))
+             (if member
+               (doseq [m (->> (concat (.getMethods c) (.getFields c))
+                              (filter #(= (.getName %) member)))]
+                 (println \" \" (if (instance? java.lang.reflect.Field m)
+                                  (str (.getSimpleName (.getType m)) \" \" (.getName m))
+                                  (signature m))))
+               (do
+                 (doseq [ctor (.getConstructors c)]
+                   (println \" \" (signature ctor)))
+                 (println \"  Members:\" (->> (concat (.getMethods c) (.getFields c))
+                                               (map #(.getName %))
+                                               (distinct)
+                                               (sort)
+                                               (clojure.string/join \" \"))))))))
+       "))
+
 (defn doc-str [{:keys [conn name]}]
   (case (:lang conn)
-    :clj (str "(with-out-str (clojure.repl/doc " name "))")
+    :clj (if (:bb? conn)
+           ;; Babashka's clojure.repl/doc doesn't know about everything, the
+           ;; metadata is always there though.
+           (str "
+                (when-let [v (resolve '" name ")]
+                  (let [{:keys [ns name arglists doc]} (meta v)]
+                    (str \"-------------------------\n\"
+                         ns \"/\" name \"\n\"
+                         (when arglists (str arglists \"\n\"))
+                         \"  \" doc \"\n\")))
+                ")
+           ;; We try a chain of lookups until one finds something: the var's
+           ;; docs, the spec under the same name, the Java class or member and
+           ;; finally a few suggestions. The doc macro won't compile when given
+           ;; a class so it's only expanded at runtime.
+           (str "
+                (let [sym '" name "
+                      spec-key (cond
+                                 (keyword? sym) sym
+                                 (simple-symbol? sym) (keyword (str *ns*) (str sym)))]
+                  (or (try
+                        (not-empty (with-out-str (eval (list 'clojure.repl/doc sym))))
+                        (catch Exception _ nil))
+                      (when spec-key
+                        (require 'clojure.spec.alpha)
+                        (when-let [spec ((resolve 'clojure.spec.alpha/get-spec) spec-key)]
+                          (str \"-------------------------\n\"
+                               spec-key \"\nSpec\n  \"
+                               (pr-str ((resolve 'clojure.spec.alpha/form) spec)) \"\n\")))
+                      " (java-doc-str name) "
+                      (when (symbol? sym)
+                        (let [similar (->> (clojure.repl/apropos
+                                             (re-pattern (java.util.regex.Pattern/quote (clojure.core/name sym))))
+                                           (take 10)
+                                           (clojure.string/join \" \"))]
+                          (str \"No doc for \" sym
+                               (when-not (clojure.string/blank? similar)
+                                 (str \", similar names: \" similar)))))))
+                "))
     :cljs (str "(with-out-str (cljs.repl/doc " name "))")))
 
+(defn source-str [{:keys [conn name]}]
+  (case (:lang conn)
+    :clj (str "(with-out-str (clojure.repl/source " name "))")
+    :cljs (str "(with-out-str (cljs.repl/source " name "))")))
+
+(defn qualified-name-str
+  "The fully qualified name of whatever var the symbol resolves to."
+  [{:keys [name]}]
+  (str "(some-> (resolve '" name ") meta ((juxt :ns :name)) (->> (clojure.string/join \"/\")))"))
+
+(defn usages-str
+  "Every form in the source files of loaded namespaces that refers to the var
+  the symbol resolves to, as [file line text]. Symbols are resolved in the
+  namespace the file belongs to so aliases and refers are found too. Sources
+  inside jars are skipped, you probably want your own code."
+  [{:keys [name]}]
+  (str "
+       (when-let [target (resolve '" name ")]
+         (vec
+           (for [ns (all-ns)
+                 :let [file (some->> (ns-interns ns) (vals) (keep (comp :file meta)) (first))
+                       url (when (and file (not= file \"NO_SOURCE_PATH\"))
+                             (or (let [f (clojure.java.io/file file)]
+                                   (when (.isAbsolute f) (.toURL f)))
+                                 (clojure.java.io/resource file)))]
+                 :when (and url (= (.getProtocol url) \"file\"))
+                 :let [path (str (clojure.java.io/as-file url))
+                       refers? (fn [form]
+                                 (some #(and (symbol? %)
+                                             (= target (try (ns-resolve ns %) (catch Exception _ nil))))
+                                       form))
+                       forms (with-open [rdr (clojure.lang.LineNumberingPushbackReader.
+                                               (clojure.java.io/reader url))]
+                               (binding [*ns* ns, *read-eval* false]
+                                 (loop [acc []]
+                                   (let [form (try
+                                                (read {:eof ::eof, :read-cond :allow} rdr)
+                                                (catch Exception _ ::eof))]
+                                     (if (= form ::eof)
+                                       acc
+                                       (recur (conj acc form)))))))]
+                 form forms
+                 node (tree-seq coll? seq form)
+                 :when (and (seq? node) (:line (meta node)) (refers? node))]
+             (let [text (pr-str node)]
+               [path (:line (meta node)) (subs text 0 (min 80 (count text)))]))))
+       "))
+
+(defn arglists-str
+  "The printed arglists of a var, nil for anything without them."
+  [{:keys [name]}]
+  (str "(some->> (resolve '" name ") meta :arglists pr-str)"))
+
+(defn apropos-str
+  "Every var in a loaded namespace who's name matches the regular expression.
+  Only the quotes are escaped, backslashes mean something in a regex literal."
+  [{:keys [conn pattern]}]
+  (let [re (str "#\"" (str/replace pattern "\"" "\\\"") "\"")]
+    (case (:lang conn)
+      :clj (str "(map str (clojure.repl/apropos " re "))")
+      :cljs (str "(map str (cljs.repl/apropos " re "))"))))
+
+(defn ns-publics-str
+  "A line per public var in the namespace with its arglists and the first line
+  of its docstring. Clojure returns nil if the namespace isn't loaded."
+  [{:keys [conn ns]}]
+  (let [lines (str "
+                    (->> (vals (ns-publics '" ns "))
+                         (map meta)
+                         (sort-by :name)
+                         (map (fn [{:keys [name arglists doc]}]
+                                (str name
+                                     (when arglists (str \" \" (pr-str arglists)))
+                                     (when doc (str \" - \" (first (clojure.string/split-lines doc)))))))
+                         (vec))
+                    ")]
+    (case (:lang conn)
+      :clj (str "(when (find-ns '" ns ")" lines ")")
+      :cljs lines)))
+
+(defn spec-str
+  "The form of the spec registered under a keyword or a function's symbol,
+  syntax quote qualifies the symbol for us."
+  [{:keys [conn name]}]
+  (let [k (if (str/starts-with? name ":") name (str "`" name))]
+    (case (:lang conn)
+      :clj (str "
+                (do
+                  (require 'clojure.spec.alpha)
+                  (some-> ((resolve 'clojure.spec.alpha/get-spec) " k ")
+                          ((resolve 'clojure.spec.alpha/form))))
+                ")
+      :cljs (str "(some-> (cljs.spec.alpha/get-spec " k ") (cljs.spec.alpha/form))"))))
+
+(defn macroexpand-str
+  "Expand the code once (:one), until it's not a macro any more (:full) or
+  recursively through every form inside it (:all). ClojureScript has no
+  macroexpand-all so :all is the same as :full there."
+  [{:keys [conn code depth]}]
+  (let [quoted (str "'" code)]
+    (case [(:lang conn) depth]
+      ([:clj :one] [:cljs :one]) (str "(macroexpand-1 " quoted ")")
+      ([:clj :full] [:cljs :full] [:cljs :all]) (str "(macroexpand " quoted ")")
+      [:clj :all] (str "(do (require 'clojure.walk) (clojure.walk/macroexpand-all " quoted "))"))))
+
+(defn inspect-str
+  "The current value of a symbol, references such as atoms are dereferenced
+  unless that would block (an unrealised delay, future or promise)."
+  [{:keys [conn name]}]
+  (case (:lang conn)
+    :clj (str "
+              (let [x " name "]
+                (if (and (instance? clojure.lang.IDeref x)
+                         (not (and (instance? clojure.lang.IPending x)
+                                   (not (realized? x)))))
+                  @x
+                  x))
+              ")
+    :cljs (str "
+               (let [x " name "]
+                 (if (and (satisfies? IDeref x)
+                          (not (and (satisfies? IPending x)
+                                    (not (realized? x)))))
+                   @x
+                   x))
+               ")))
+
+(defn require-str [{:keys [ns reload?]}]
+  (str "(require '" ns (when reload? " :reload") ")"))
+
+(defn unmap-str
+  "Remove the mappings for the names from the namespace, returns the names."
+  [{:keys [ns names]}]
+  (str "(do "
+       (str/join " " (map #(str "(ns-unmap '" ns " '" % ")") names))
+       " '[" (str/join " " names) "])"))
+
+(defn unalias-str [{:keys [ns alias]}]
+  (str "(ns-unalias '" ns " '" alias ")"))
+
+(defn unused-requires-str
+  "The aliases and (non clojure.core) refers of the namespace that the code in
+  its file never uses. The file is read in the namespace so ::alias/keys and
+  syntax quoted symbols come out fully qualified, which we check for too."
+  [{:keys [ns path]}]
+  (str "
+       (let [target (the-ns '" ns ")
+             eof (Object.)
+             forms (with-open [rdr (java.io.PushbackReader. (clojure.java.io/reader \"" (util/escape-quotes path) "\"))]
+                     (binding [*ns* target
+                               *read-eval* false
+                               *default-data-reader-fn* tagged-literal]
+                       (doall (take-while #(not (identical? % eof))
+                                          (repeatedly #(read {:eof eof, :read-cond :allow} rdr))))))
+             body (remove #(and (seq? %) (= 'ns (first %))) forms)
+             names (filter #(or (symbol? %) (keyword? %)) (tree-seq coll? seq body))
+             used-nss (set (keep namespace names))
+             used-names (set (map name (filter #(and (symbol? %) (nil? (namespace %))) names)))]
+         {:aliases (sort (for [[alias aliased] (ns-aliases target)
+                               :when (not (or (used-nss (str alias))
+                                              (used-nss (str (ns-name aliased)))))]
+                           (str alias)))
+          :refers (sort (for [[sym v] (ns-refers target)
+                              :let [var-ns (str (ns-name (:ns (meta v))))]
+                              :when (not (or (= var-ns \"clojure.core\")
+                                             (used-names (str sym))
+                                             (used-nss var-ns)))]
+                          (str sym)))})
+       "))
+
+(defn ns-interns-str [{:keys [ns]}]
+  (str "(map str (keys (ns-interns '" ns ")))"))
+
+(defn refresh-str
+  "Reload changed namespaces (or all of them) with tools.namespace, returns
+  what it printed and, if something didn't load, the error along with the file
+  and line the compiler blamed."
+  [{:keys [all?]}]
+  (str "
+       (do
+         (require 'clojure.tools.namespace.repl)
+         (let [out (java.io.StringWriter.)
+               result (binding [*out* out]
+                        ((resolve 'clojure.tools.namespace.repl/" (if all? "refresh-all" "refresh") ")))]
+           {:out (str out)
+            :error (when (instance? Throwable result)
+                     (let [data (some ex-data (take-while some? (iterate ex-cause result)))
+                           source (:clojure.error/source data)]
+                       {:message (.getMessage (or (ex-cause result) result))
+                        :file (some-> source
+                                      (clojure.java.io/resource)
+                                      (str)
+                                      (clojure.string/replace #\"^file:\" \"\"))
+                        :line (:clojure.error/line data)}))}))
+       "))
+
+(defn add-lib-str
+  "Load a library into the running REPL. Clojure 1.12 has add-lib built in and
+  can find the latest version for us, before that we need the add-lib branch
+  of tools.deps.alpha and a coordinate."
+  [{:keys [lib coord]}]
+  (str "
+       (if-let [add-lib (try
+                          (require 'clojure.repl.deps)
+                          (resolve 'clojure.repl.deps/add-lib)
+                          (catch java.io.FileNotFoundException _))]
+         (add-lib '" lib (when coord (str " " (pr-str coord))) ")
+         (do
+           (require 'clojure.tools.deps.alpha.repl)
+           ((resolve 'clojure.tools.deps.alpha.repl/add-lib) '" lib " " (pr-str coord) ")))
+       "))
+
+(defn classpath-str
+  "The JVM's classpath entries containing the query, split into the
+  directories and the jars (or anything else)."
+  [{:keys [query]}]
+  (str "
+       (->> (clojure.string/split (System/getProperty \"java.class.path\")
+                                  (re-pattern java.io.File/pathSeparator))
+            (remove clojure.string/blank?)
+            (filter #(clojure.string/includes? % \"" (util/escape-quotes (or query "")) "\"))
+            (distinct)
+            (group-by #(if (.isDirectory (clojure.java.io/file %)) :dirs :jars)))
+       "))
+
 (defn load-file-str [path]
-  (str "(load-file \"" path "\")"))
+  (str "(load-file \"" (util/escape-quotes path) "\")"))
+
+(defn- ns-completions-str
+  "Completions from the vars, aliases and aliased publics the namespace can see,
+  for when compliment isn't around. The candidates look like compliment's.
+  They're ranked the same way util/fuzzy-rank does, minus the word frequencies,
+  before being cut down so the best matches always make it back. Every match
+  contains the prefix in order so the edit distance is just the extra length."
+  [ns prefix]
+  (str "
+       (let [prefix \"" (util/escape-quotes prefix) "\"
+             ns (or (find-ns '" (or ns "user") ") (find-ns 'user))
+             var-info (fn [candidate v]
+                        (let [{:keys [arglists macro] :as m} (meta v)]
+                          {:candidate candidate
+                           :type (cond macro :macro, arglists :function, :else :var)
+                           :ns (str (:ns m))
+                           :arglists (some->> arglists (map pr-str))}))
+             candidates (if-let [[_ alias-name] (re-matches #\"(.+)/.*\" prefix)]
+                          (when-let [alias-ns (get (ns-aliases ns) (symbol alias-name))]
+                            (for [[sym v] (ns-publics alias-ns)]
+                              (var-info (str alias-name \"/\" sym) v)))
+                          (concat
+                            (for [[sym v] (ns-map ns) :when (var? v)]
+                              (var-info (str sym) v))
+                            (for [[alias-sym _] (ns-aliases ns)]
+                              {:candidate (str alias-sym), :type :namespace})))]
+         (let [fuzzy (re-pattern (str \"^\" (apply str (map #(str (java.util.regex.Pattern/quote (str %)) \".*\") prefix))))]
+           (->> candidates
+                (filter #(re-find fuzzy (:candidate %)))
+                (sort-by (fn [{:keys [candidate]}]
+                           [(if (clojure.string/starts-with? candidate prefix) 0 1)
+                            (count candidate)
+                            candidate]))
+                (take 500))))
+       "))
 
 (defn completions-str [{:keys [ns]} {:keys [conn prefix context]}]
   (case (:lang conn)
+    ;; Babashka can't load compliment but it can scan namespaces.
     :clj
-    (str "
-         (when-let [completions (resolve 'compliment.core/completions)]
+    (if (:bb? conn)
+      (ns-completions-str ns prefix)
+      (str "
+         (if-let [completions (resolve 'compliment.core/completions)]
            (completions
              \"" (util/escape-quotes prefix) "\"
              {:ns (find-ns '" (or ns "user") ")
+              :extra-metadata #{:arglists}
               " (when context
                   (str ":context \"" (util/escape-quotes context) "\""))
-             "}))
-         ")
+             "})
+           " (ns-completions-str ns prefix) ")
+         "))
 
     ;; ClojureScript isn't supported by compliment right now.
     :cljs "[]"))
@@ -123,22 +804,191 @@
                (update 2 dec))))
        "))
 
-(defn run-tests-str [{:keys [targets conn]}]
-  (let [targets-str (->> targets
-                         (map #(str "'" %))
-                         (str/join " "))]
+(defn stack-frames-str
+  "Find the source of each [class method file line] frame in a Throwable->map
+  trace. The file is only a name so we look it up on the classpath in the
+  class's package, frames we can't find are dropped."
+  [{:keys [trace]}]
+  (str "
+       (keep (fn [[class method file line]]
+               (let [class-name (first (clojure.string/split (str class) #\"\\$\"))
+                     package (second (re-matches #\"(.*)\\.[^.]+\" class-name))
+                     path (some-> (clojure.java.io/resource (str (some-> package (clojure.string/replace \".\" \"/\") (str \"/\")) file))
+                                  (str)
+                                  (clojure.string/replace #\"^file:\" \"\")
+                                  (clojure.string/replace #\"^jar:file\" \"zipfile\")
+                                  (clojure.string/replace #\"\\.jar!/\" \".jar::\"))]
+                 (when (and path file)
+                   {:filename path
+                    :lnum line
+                    :text (str (clojure.repl/demunge (str class)) \" \" method)})))
+             '" (pr-str (vec trace)) ")
+       "))
+
+(defn- test-report-str
+  "Run the tests with the report going into a string, returns the output along
+  with the counts clojure.test summarised it with, the names of the test vars
+  that failed or threw, where each failure happened and whether each test var
+  passed. clojure.test only gives us the file name so the full path comes from
+  the test var. Anything the tests print is captured as :stdout so it doesn't
+  end up mixed into the report, run-str can still print to progress-out."
+  [run-str]
+  (str "
+       (let [out (java.io.StringWriter.)
+             stdout (java.io.StringWriter.)
+             progress-out *out*
+             failed (atom [])
+             failures (atom [])
+             results (atom [])
+             report clojure.test/report
+             full-path (fn [file]
+                         (when file
+                           (let [f (clojure.java.io/file file)]
+                             (if (.isAbsolute f)
+                               (str f)
+                               (some-> (clojure.java.io/resource file) (str) (clojure.string/replace #\"^file:\" \"\"))))))
+             summary (binding [clojure.test/*test-out* out
+                               *out* stdout
+                               clojure.test/report (fn [m]
+                                                     (when (#{:fail :error} (:type m))
+                                                       (when-let [v (first clojure.test/*testing-vars*)]
+                                                         (let [{var-ns :ns, var-name :name, :keys [file line]} (meta v)
+                                                               test-name (str var-ns \"/\" var-name)]
+                                                           (swap! failed conj test-name)
+                                                           (swap! failures conj
+                                                                  {:file (full-path file)
+                                                                   :line (or (:line m) line)
+                                                                   :text (str test-name \" \" (name (:type m)) \": \"
+                                                                              (some-> (:message m) (str \" \"))
+                                                                              \"expected \" (pr-str (:expected m))
+                                                                              \", actual \" (pr-str (:actual m)))}))))
+                                                     (when (= (:type m) :end-test-var)
+                                                       (let [{var-ns :ns, var-name :name, :keys [file line]} (meta (:var m))]
+                                                         (swap! results conj
+                                                                {:file (full-path file)
+                                                                 :line line
+                                                                 :passed (not-any? #{(str var-ns \"/\" var-name)} @failed)})))
+                                                     (report m))]
+                       " run-str ")]
+         {:out (str out)
+          :stdout (str stdout)
+          :summary (select-keys summary [:test :pass :fail :error])
+          :failed (vec (distinct @failed))
+          :failures @failures
+          :results @results})
+       "))
+
+(defn- kaocha-str
+  "Run tests through kaocha.repl instead of clojure.test, the args (ids such
+  as namespace or var symbols) are passed straight to the run fn. Kaocha does
+  its own reporting so we capture that and translate its totals."
+  [run-fn args-str]
+  (str "
+       (do
+         (require 'kaocha.repl)
+         (let [out (java.io.StringWriter.)
+               totals (binding [*out* out]
+                        ((resolve 'kaocha.repl/" run-fn ") " args-str "))]
+           {:out (str out)
+            :summary {:test (:kaocha.result/count totals)
+                      :pass (:kaocha.result/pass totals)
+                      :fail (:kaocha.result/fail totals)
+                      :error (:kaocha.result/error totals)}}))
+       "))
+
+(defn- quoted-str [syms]
+  (->> syms
+       (map #(str "'" %))
+       (str/join " ")))
+
+(defn run-tests-str
+  "Run the tests in the target namespaces. Clojure namespaces are required
+  first, test namespaces with :reload so you see your latest saved tests, and
+  any that don't exist are skipped."
+  [{:keys [targets conn]}]
+  (let [targets-str (quoted-str targets)]
     (case (:lang conn)
       :clj
+      (if (= (:test-runner conn) :kaocha)
+        (kaocha-str "run" targets-str)
+        (test-report-str
+          (str "
+               (apply clojure.test/run-tests
+                      (keep (fn [ns]
+                              (try
+                                (if (clojure.string/ends-with? (str ns) \"-test\")
+                                  (require ns :reload)
+                                  (require ns))
+                                (find-ns ns)
+                                (catch java.io.FileNotFoundException _
+                                  (find-ns ns))))
+                            (distinct [" targets-str "])))
+               ")))
+
+      :cljs
       (str "
            (with-out-str
-             (binding [clojure.test/*test-out* *out*]
-               (apply clojure.test/run-tests (keep find-ns #{" targets-str "}))))
-           ")
+             (cljs.test/run-tests " targets-str "))
+           "))))
+
+(defn run-test-str
+  "Run some deftests by (qualified) name."
+  [{:keys [names conn]}]
+  (let [vars-str (->> names
+                      (map #(str "(var " % ")"))
+                      (str/join " "))]
+    (case (:lang conn)
+      :clj
+      (if (= (:test-runner conn) :kaocha)
+        (kaocha-str "run" (quoted-str names))
+        (test-report-str
+          (str "
+               (binding [clojure.test/*report-counters* (ref clojure.test/*initial-report-counters*)]
+                 (clojure.test/test-vars [" vars-str "])
+                 @clojure.test/*report-counters*)
+               ")))
 
       :cljs
       (str "
            (with-out-str
-             (cljs.test/run-tests " targets-str "))
+             (cljs.test/test-vars [" vars-str "]))
+           "))))
+
+(defn run-project-tests-str
+  "Find every namespace in the source directories on the classpath who's name
+  matches the pattern, then require and test them one at a time. A progress
+  line is printed after each so you can see how it's going. Kaocha
+  connections run their whole configured suite instead."
+  [{:keys [pattern conn]}]
+  (if (= (:test-runner conn) :kaocha)
+    (kaocha-str "run-all" "")
+    (test-report-str
+      (str "
+           (let [pattern #\"" (str/replace pattern "\"" "\\\"") "\"
+                 totals (atom {:test 0, :pass 0, :fail 0, :error 0})
+                 nss (->> (clojure.string/split (System/getProperty \"java.class.path\")
+                                                (re-pattern java.io.File/pathSeparator))
+                          (map clojure.java.io/file)
+                          (filter #(.isDirectory %))
+                          (mapcat (fn [root]
+                                    (for [f (file-seq root)
+                                          :when (re-find #\"\\.cljc?$\" (.getName f))]
+                                      (-> (str (.relativize (.toPath root) (.toPath f)))
+                                          (clojure.string/replace #\"\\.cljc?$\" \"\")
+                                          (clojure.string/replace java.io.File/separator \".\")
+                                          (clojure.string/replace \"_\" \"-\")
+                                          (symbol)))))
+                          (filter #(re-find pattern (str %)))
+                          (distinct)
+                          (sort))]
+             (doseq [[n ns] (map-indexed vector nss)]
+               (require ns :reload)
+               (let [{:keys [test fail error] :as counts} (clojure.test/test-ns ns)]
+                 (swap! totals #(merge-with + % (select-keys counts (keys %))))
+                 (binding [*out* progress-out]
+                   (println (str \"[\" (inc n) \"/\" (count nss) \"] \" ns \": \"
+                                 test \" tests, \" fail \" failures, \" error \" errors\")))))
+             @totals)
            "))))
 
 (defn run-all-tests-str [{:keys [re conn]}]
@@ -147,11 +997,9 @@
 
     (case (:lang conn)
       :clj
-      (str "
-           (with-out-str
-             (binding [clojure.test/*test-out* *out*]
-               (clojure.test/run-all-tests" re-str ")))
-           ")
+      (if (= (:test-runner conn) :kaocha)
+        (kaocha-str "run-all" "")
+        (test-report-str (str "(clojure.test/run-all-tests" re-str ")")))
 
       :cljs
       (str "
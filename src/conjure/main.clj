@@ -15,6 +15,7 @@
   "Performs any necessary clean up and calls `(System/exit status)`."
   []
   (log/info "Shutting down")
+  (prepl/kill-all!)
   (shutdown-agents)
   (flush)
   (binding [*out* *err*] (flush))
@@ -42,18 +43,137 @@
   (when-let [new-conn (parse-user-edn ::prepl/new-conn (first params))]
     (prepl/add! new-conn)))
 
+(defmethod rpc/handle-notify :auto-add [_]
+  (action/auto-add))
+
 (defmethod rpc/handle-notify :remove [{:keys [params]}]
   (when-let [tag (parse-user-edn ::prepl/tag (first params))]
     (prepl/remove! tag)))
 
+(defmethod rpc/handle-notify :rename [{:keys [params]}]
+  (let [[from to] (map #(parse-user-edn ::prepl/tag %) params)]
+    (when (and from to)
+      (action/rename from to))))
+
+(defmethod rpc/handle-notify :bindings [{:keys [params]}]
+  (let [[tag-str bindings-str] (str/split (str/trim (first params)) #"\s+" 2)
+        tag (parse-user-edn ::prepl/tag tag-str)
+        bindings (when tag
+                   (parse-user-edn ::prepl/bindings (or bindings-str "{}")))]
+    (when bindings
+      (prepl/set-bindings! tag bindings))))
+
+(defmethod rpc/handle-notify :unmap [{:keys [params]}]
+  (when-let [names (seq (remove str/blank? (str/split (str/trim (first params)) #"\s+")))]
+    (action/unmap names)))
+
+(defmethod rpc/handle-notify :unmap-stale [_]
+  (action/unmap-stale))
+
+(defmethod rpc/handle-notify :unalias [{:keys [params]}]
+  (when-not (str/blank? (first params))
+    (action/unalias (str/trim (first params)))))
+
+(defmethod rpc/handle-notify :unused-requires [_]
+  (action/unused-requires))
+
+(defmethod rpc/handle-notify :classpath [{:keys [params]}]
+  (action/classpath (when-not (str/blank? (first params))
+                      (str/trim (first params)))))
+
+(defmethod rpc/handle-notify :add-lib [{:keys [params]}]
+  (let [[lib-str coord-str] (str/split (str/trim (first params)) #"\s+" 2)
+        lib (parse-user-edn qualified-symbol? lib-str)
+        coord (when (and lib coord-str)
+                (parse-user-edn map? coord-str))]
+    (when (and lib (or coord (nil? coord-str)))
+      (action/add-lib lib coord))))
+
 (defmethod rpc/handle-notify :remove-all [_]
   (prepl/remove-all!))
 
+(defmethod rpc/handle-notify :reconnect-all [_]
+  (prepl/reconnect-all!))
+
 (defmethod rpc/handle-notify :status [_]
   (prepl/status))
 
+(defmethod rpc/handle-notify :route [{:keys [params]}]
+  (action/route (first params)))
+
+(defmethod rpc/handle-notify :interrupt [{:keys [params]}]
+  (let [[tag-str] params]
+    (if (str/blank? tag-str)
+      (action/interrupt nil)
+      (when-let [tag (parse-user-edn ::prepl/tag tag-str)]
+        (action/interrupt tag)))))
+
+(defmethod rpc/handle-notify :toggle-reflection [{:keys [params]}]
+  (let [[tag-str] params]
+    (if (str/blank? tag-str)
+      (action/toggle-reflection nil)
+      (when-let [tag (parse-user-edn ::prepl/tag tag-str)]
+        (action/toggle-reflection tag)))))
+
+(defmethod rpc/handle-notify :toggle-dry-run [_]
+  (action/toggle-dry-run))
+
+(defmethod rpc/handle-notify :stdin [{:keys [params]}]
+  (let [[tag-str text] (str/split (first params) #" " 2)]
+    (if (str/starts-with? tag-str ":")
+      (when-let [tag (parse-user-edn ::prepl/tag tag-str)]
+        (action/stdin {:tag tag, :text (or text "")}))
+      (action/stdin {:text (first params)}))))
+
+(defmethod rpc/handle-notify :custom [{:keys [params]}]
+  (let [[args-str word] params
+        [name args] (str/split (str/trim args-str) #"\s+" 2)]
+    (action/custom name args word)))
+
+(defmethod rpc/handle-notify :tasks [_]
+  (action/tasks))
+
+(defmethod rpc/handle-notify :run-task [{:keys [params]}]
+  (when-not (str/blank? (first params))
+    (action/run-task (str/replace (str/trim (first params)) #"^:" ""))))
+
+(defmethod rpc/handle-notify :dev-setup [{:keys [params]}]
+  (let [[tag-str] params]
+    (if (str/blank? tag-str)
+      (action/dev-setup nil)
+      (when-let [tag (parse-user-edn ::prepl/tag tag-str)]
+        (action/dev-setup tag)))))
+
+(defmethod rpc/handle-notify :system [{:keys [params]}]
+  (let [[op-str tag-str] (str/split (str/trim (first params)) #"\s+" 2)]
+    (if (str/blank? tag-str)
+      (action/system (keyword op-str) nil)
+      (when-let [tag (parse-user-edn ::prepl/tag tag-str)]
+        (action/system (keyword op-str) tag)))))
+
 (defmethod rpc/handle-notify :eval [{:keys [params]}]
-  (action/eval* (first params)))
+  (action/eval* {:code (first params)}))
+
+(defmethod rpc/handle-notify :eval-on [{:keys [params]}]
+  (let [[tag-str code] (str/split (str/trim (first params)) #"\s+" 2)]
+    (when-let [tag (parse-user-edn ::prepl/tag tag-str)]
+      (action/eval* {:tag tag, :code code}))))
+
+(defmethod rpc/handle-notify :eval-in [{:keys [params]}]
+  (let [[ns code] (str/split (str/trim (first params)) #"\s+" 2)]
+    (action/eval* {:ns (symbol ns), :code code})))
+
+(defmethod rpc/handle-notify :in-ns [{:keys [params]}]
+  (let [[ns-str] params]
+    (action/in-ns* (when-not (str/blank? ns-str)
+                     (symbol (str/trim ns-str))))))
+
+(defmethod rpc/handle-notify :eval-last [{:keys [params]}]
+  (let [[tag-str] params]
+    (if (str/blank? tag-str)
+      (action/eval-last nil)
+      (when-let [tag (parse-user-edn ::prepl/tag tag-str)]
+        (action/eval-last tag)))))
 
 (defmethod rpc/handle-notify :eval-current-form [_]
   (action/eval-current-form))
@@ -64,36 +184,138 @@
 (defmethod rpc/handle-notify :eval-selection [_]
   (action/eval-selection))
 
+(defmethod rpc/handle-notify :eval-replace [_]
+  (action/eval-replace))
+
+(defmethod rpc/handle-notify :eval-comment [_]
+  (action/eval-comment))
+
 (defmethod rpc/handle-notify :eval-buffer [_]
   (action/eval-buffer))
 
+(defmethod rpc/handle-notify :macroexpand [{:keys [params]}]
+  (let [[depth code] params]
+    (action/macroexpand* {:depth (keyword depth), :code code})))
+
 (defmethod rpc/handle-notify :load-file [{:keys [params]}]
   (action/load-file* (first params)))
 
+(defmethod rpc/handle-notify :inspect [{:keys [params]}]
+  (action/inspect (first params)))
+
 (defmethod rpc/handle-notify :doc [{:keys [params]}]
   (action/doc (first params)))
 
+(defmethod rpc/handle-notify :source [{:keys [params]}]
+  (action/source (first params)))
+
+(defmethod rpc/handle-notify :examples [{:keys [params]}]
+  (action/examples (first params)))
+
+(defmethod rpc/handle-notify :ns-publics [{:keys [params]}]
+  (let [[ns-str] params]
+    (action/ns-publics* (when-not (str/blank? ns-str)
+                          (symbol (str/trim ns-str))))))
+
+(defmethod rpc/handle-notify :spec [{:keys [params]}]
+  (action/spec (first params)))
+
+(defmethod rpc/handle-notify :apropos [{:keys [params]}]
+  (action/apropos (first params)))
+
 (defmethod rpc/handle-notify :open-log [_]
   (ui/upsert-log {:focus? true
                   :resize? true
                   :width :large}))
 
+(defmethod rpc/handle-notify :expand-last [_]
+  (ui/expand-last))
+
 (defmethod rpc/handle-notify :close-log [_]
   (ui/close-log))
 
+(defmethod rpc/handle-notify :stack-trace [{:keys [params]}]
+  (let [[tag-str] params]
+    (if (str/blank? tag-str)
+      (action/stack-trace nil)
+      (when-let [tag (parse-user-edn ::prepl/tag tag-str)]
+        (action/stack-trace tag)))))
+
+(defmethod rpc/handle-notify :expand-elision [{:keys [params]}]
+  (let [[tag-str] params]
+    (if (str/blank? tag-str)
+      (action/expand-elision nil)
+      (when-let [tag (parse-user-edn ::prepl/tag tag-str)]
+        (action/expand-elision tag)))))
+
+(defmethod rpc/handle-notify :yank-result [{:keys [params]}]
+  (let [args (remove str/blank? (str/split (str/trim (first params)) #"\s+"))
+        {tag-strs true registers false} (group-by #(str/starts-with? % ":") args)
+        tag (some->> (first tag-strs) (parse-user-edn ::prepl/tag))]
+    (when (or tag (empty? tag-strs))
+      (action/yank-result {:tag tag, :register (first registers)}))))
+
+(defmethod rpc/handle-notify :toggle-log-follow [_]
+  (ui/toggle-log-follow))
+
+(defmethod rpc/handle-notify :clear-log [_]
+  (ui/clear-log))
+
+(defmethod rpc/handle-notify :log-only [{:keys [params]}]
+  (when-let [tags (seq (keep #(parse-user-edn ::prepl/tag %) (str/split (str/trim (first params)) #"\s+")))]
+    (ui/filter-log! {:only (set tags)})))
+
+(defmethod rpc/handle-notify :log-hide [{:keys [params]}]
+  (when-let [tags (seq (keep #(parse-user-edn ::prepl/tag %) (str/split (str/trim (first params)) #"\s+")))]
+    (ui/filter-log! {:hide (set tags)})))
+
+(defmethod rpc/handle-notify :log-show-all [_]
+  (ui/filter-log! nil))
+
 (defmethod rpc/handle-request :completions [{:keys [params]}]
   (action/completions (first params)))
 
+(defmethod rpc/handle-request :document-symbols [_]
+  (action/document-symbols))
+
+(defmethod rpc/handle-request :arglists [{:keys [params]}]
+  (action/arglists (first params)))
+
 (defmethod rpc/handle-request :get-rpc-port [_]
   rpc/port)
 
 (defmethod rpc/handle-notify :definition [{:keys [params]}]
   (action/definition (first params)))
 
+(defmethod rpc/handle-notify :usages [{:keys [params]}]
+  (action/usages (first params)))
+
 (defmethod rpc/handle-notify :run-tests [{:keys [params]}]
   (action/run-tests (->> (str/split (first params) #"\s+")
                          (remove str/blank?))))
 
+(defmethod rpc/handle-notify :run-current-test [_]
+  (action/run-current-test))
+
+(defmethod rpc/handle-notify :test-on-save [_]
+  (action/test-on-save))
+
+(defmethod rpc/handle-notify :run-project-tests [{:keys [params]}]
+  (action/run-project-tests (when-not (str/blank? (first params))
+                              (str/trim (first params)))))
+
+(defmethod rpc/handle-notify :retest-failed [_]
+  (action/retest-failed))
+
+(defmethod rpc/handle-notify :jump-to-failure [_]
+  (action/jump-to-failure))
+
 (defmethod rpc/handle-notify :run-all-tests [{:keys [params]}]
   (action/run-all-tests (when-not (str/blank? (first params))
                           (first params))))
+
+(defmethod rpc/handle-notify :refresh [_]
+  (action/refresh false))
+
+(defmethod rpc/handle-notify :refresh-all [_]
+  (action/refresh true))
@@ -0,0 +1,192 @@
+(ns conjure.nrepl
+  "nREPL connections that look like prepl connections from the outside."
+  (:require [clojure.core.async :as a]
+            [taoensso.timbre :as log]
+            [conjure.ui :as ui]
+            [clojure.string :as str]
+            [conjure.util :as util]
+            [conjure.code :as code]
+            [conjure.socket :as socket])
+  (:import [java.io ByteArrayOutputStream PushbackInputStream OutputStream]))
+
+(defn- write-str [^OutputStream out ^String s]
+  (.write out (.getBytes s "UTF-8")))
+
+(defn- encode* [out data]
+  (cond
+    (integer? data)
+    (write-str out (str "i" data "e"))
+
+    (or (string? data) (keyword? data) (symbol? data))
+    (let [b (.getBytes ^String (if (string? data) data (name data)) "UTF-8")]
+      (write-str out (str (count b) ":"))
+      (.write ^OutputStream out ^bytes b))
+
+    (map? data)
+    (do
+      (write-str out "d")
+      (doseq [[k v] (sort-by (comp name key) data)]
+        (encode* out (name k))
+        (encode* out v))
+      (write-str out "e"))
+
+    (sequential? data)
+    (do
+      (write-str out "l")
+      (doseq [item data]
+        (encode* out item))
+      (write-str out "e"))
+
+    :else
+    (throw (ex-info "Can't bencode value" {:data data}))))
+
+(defn encode
+  "Encode some data as bencode, returns a byte array."
+  [data]
+  (let [out (ByteArrayOutputStream.)]
+    (encode* out data)
+    (.toByteArray out)))
+
+(defn- read-until
+  "Read bytes up to (and dropping) the terminator, returns them as a string."
+  [^PushbackInputStream in terminator]
+  (loop [acc (StringBuilder.)]
+    (let [b (.read in)]
+      (cond
+        (= b -1) (throw (ex-info "Unexpected end of bencode stream" {}))
+        (= (char b) terminator) (str acc)
+        :else (recur (.append acc (char b)))))))
+
+(defn decode
+  "Read the next bencode value from the stream. Strings are decoded as UTF-8
+  and dictionaries are returned with string keys. Returns nil at the end of the
+  stream."
+  [^PushbackInputStream in]
+  (let [b (.read in)]
+    (when-not (= b -1)
+      (case (char b)
+        \i (Long/parseLong (read-until in \e))
+        \l (loop [acc []]
+             (let [b (.read in)]
+               (if (= (char b) \e)
+                 acc
+                 (do
+                   (.unread in b)
+                   (recur (conj acc (decode in)))))))
+        \d (loop [acc {}]
+             (let [b (.read in)]
+               (if (= (char b) \e)
+                 acc
+                 (do
+                   (.unread in b)
+                   (recur (assoc acc (decode in) (decode in)))))))
+        (do
+          (.unread in b)
+          (let [length (Long/parseLong (read-until in \:))
+                buf (byte-array length)]
+            (loop [offset 0]
+              (when (< offset length)
+                (let [n (.read in buf offset (- length offset))]
+                  (when (= n -1)
+                    (throw (ex-info "Unexpected end of bencode stream" {})))
+                  (recur (+ offset n)))))
+            (String. buf "UTF-8")))))))
+
+(defn ->prepl
+  "Translate an nREPL response into the prepl style maps the rest of the system
+  expects. An eval that threw or was interrupted never sends a value so its
+  status becomes an error :ret instead, otherwise nobody would ever hear back.
+  Responses we don't care about (status updates etc) return nil unless the
+  eval is waiting for stdin."
+  [msg]
+  (cond
+    (contains? msg "value") {:tag :ret, :val (get msg "value"), :ns (get msg "ns")}
+    (contains? msg "out") (let [out (get msg "out")]
+                            (if (str/starts-with? out code/tap-marker)
+                              {:tag :tap, :val (str/trim (subs out (count code/tap-marker)))}
+                              {:tag :out, :val out}))
+    (contains? msg "err") {:tag :err, :val (get msg "err")}
+    (some #{"eval-error"} (get msg "status")) {:tag :ret
+                                               :val (pr-str [:error (cond-> {:ex (get msg "ex")}
+                                                                      (contains? msg "root-ex")
+                                                                      (assoc :root-ex (get msg "root-ex")))])}
+    (some #{"interrupted"} (get msg "status")) {:tag :ret
+                                                :val (pr-str [:error {:cause "Interrupted"}])}
+    (some #{"need-input"} (get msg "status")) {:tag :need-input}))
+
+(defn connect
+  "Connect to an nREPL server and return channels that behave like the ones
+  from conjure.prepl/connect. Strings on the eval channel are sent as eval ops
+  within a single session and the responses come out of the read channel as
+  prepl style maps. Closing the eval channel closes the socket which in turn
+  closes the read channel. Anything put onto the interrupt channel interrupts
+  the current eval and strings on the stdin channel are sent as its input."
+  [{:keys [tag] :as opts}]
+  (let [[eval-chan read-chan interrupt-chan stdin-chan] (repeatedly #(a/chan 32))
+        conn! (promise)
+        send! (fn [{:keys [^OutputStream output]} msg]
+                (log/trace "Sending nREPL message to" tag "-" msg)
+                (locking output
+                  (doto output
+                    (.write ^bytes (encode msg))
+                    (.flush))))]
+
+    (util/thread
+      "nREPL reader loop"
+      (try
+        (log/info "Connecting through nREPL" tag)
+        (let [{:keys [input output close]} (socket/open opts)
+              input (PushbackInputStream. input)
+              conn {:close close
+                    :output output}]
+          (send! conn {:op "clone"})
+          (let [session (get (decode input) "new-session")]
+            (deliver conn! (assoc conn :session session))
+            (loop []
+              (when-let [msg (decode input)]
+                (log/trace "Read from nREPL" tag "-" msg)
+                (when-let [out (->prepl msg)]
+                  (a/>!! read-chan out))
+                (recur)))))
+
+        (catch Exception e
+          (log/error "Error from nREPL:" e)
+
+          ;; Once we're connected errors are usually just the socket closing.
+          (when-not (realized? conn!)
+            (ui/error "Error from" tag e)))
+
+        (finally
+          (log/trace "Exited nREPL reader, cleaning up" tag)
+          (deliver conn! nil)
+          (a/close! read-chan))))
+
+    (util/thread
+      "nREPL writer loop"
+      (when-let [conn @conn!]
+        (try
+          (loop []
+            (let [[v ch] (a/alts!! [interrupt-chan stdin-chan eval-chan] :priority true)]
+              (when v
+                (send! conn (condp = ch
+                              interrupt-chan {:op "interrupt"
+                                              :session (:session conn)}
+                              stdin-chan {:op "stdin"
+                                          :stdin v
+                                          :session (:session conn)}
+                              {:op "eval"
+                               :code v
+                               :session (:session conn)}))
+                (recur))))
+
+          (catch Exception e
+            (log/error "Error from eval-chan writing:" e))
+
+          (finally
+            (log/trace "Exited eval-chan loop, closing nREPL socket" tag)
+            ((:close conn))))))
+
+    {:eval-chan eval-chan
+     :read-chan read-chan
+     :interrupt-chan interrupt-chan
+     :stdin-chan stdin-chan}))
@@ -1,11 +1,14 @@
 (ns conjure.nvim
-  (:require [conjure.nvim.api :as api]
+  (:require [clojure.string :as str]
+            [conjure.nvim.api :as api]
             [conjure.code :as code]
             [conjure.util :as util]))
 
 (defn current-ctx
   "Context contains useful data that we don't watch to fetch twice while
-  building code to eval. This function performs those costly calls."
+  building code to eval. This function performs those costly calls. The
+  namespace is parsed from the ns form at the top of the buffer, so scratch
+  buffers and files outside of the classpath work as long as they have one."
   []
   (let [line-count 25
         buf (api/call (api/get-current-buf))
@@ -18,14 +21,16 @@
            (api/get-current-win)])]
     (loop [sample-lines sample-lines
            line-count line-count]
-      (let [ns (code/parse-ns (util/join-lines sample-lines))
+      (let [sample (util/join-lines sample-lines)
+            ns (code/parse-ns sample)
             next-line-count (* line-count 2)]
         (if (and (nil? ns) (< line-count buf-length))
           (recur (api/call (get-lines next-line-count)) next-line-count)
           {:path path
            :buf buf
            :win win
-           :ns ns})))))
+           :ns ns
+           :aliases (code/parse-aliases sample)})))))
 
 (defn- read-range
   "Given some lines, start column, and end column it will trim the first and
@@ -118,6 +123,35 @@
        ((if root? last first)
         (sort-by (comp count :form) text))))))
 
+(defn replace-form
+  "Replace a form found by read-form (or read-selection) in the current buffer
+  with some other text, keeping whatever surrounds it on the first and last
+  lines."
+  [{:keys [form origin]} replacement]
+  (let [[row col] origin
+        form-lines (util/split-lines form)
+        end-row (+ row (dec (count form-lines)))
+        buf (api/call (api/get-current-buf))
+        lines (api/call (api/buf-get-lines buf {:start (dec row), :end end-row}))
+        prefix (subs (first lines) 0 (dec col))
+        suffix (subs (last lines)
+                     (min (count (last lines))
+                          (if (= (count form-lines) 1)
+                            (+ (dec col) (count form))
+                            (count (last form-lines)))))]
+    (api/call
+      (api/buf-set-lines buf
+                         {:start (dec row), :end end-row}
+                         (util/split-lines (str prefix replacement suffix))))))
+
+(defn insert-after-form
+  "Insert some lines into the current buffer below the last line of a form
+  found by read-form."
+  [{:keys [form origin]} lines]
+  (let [end-row (+ (first origin) (dec (count (util/split-lines form))))
+        buf (api/call (api/get-current-buf))]
+    (api/call (api/buf-set-lines buf {:start end-row, :end end-row} lines))))
+
 (defn read-buffer
   "Read the entire current buffer into a string."
   []
@@ -125,6 +159,52 @@
       (api/buf-get-lines {:start 0, :end -1}) (api/call)
       (util/join-lines)))
 
+(defn config
+  "Read a g:conjure_* option, :result-register is g:conjure_result_register.
+  Returns the default when it isn't set."
+  ([k] (config k nil))
+  ([k default]
+   (let [value (api/call (api/eval* (str "get(g:, 'conjure_" (util/kw->snake k) "', v:null)")))]
+     (if (nil? value)
+       default
+       value))))
+
+(defn configs
+  "Read several g:conjure_* options in one go, takes a map of keys to defaults
+  and returns a map of keys to values."
+  [defaults]
+  (let [ks (keys defaults)
+        values (api/call-batch
+                 (for [k ks]
+                   (api/eval* (str "get(g:, 'conjure_" (util/kw->snake k) "', v:null)"))))]
+    (into {}
+          (map (fn [k value]
+                 [k (if (nil? value) (get defaults k) value)]))
+          ks values)))
+
+(defn set-register
+  "Place some text in a register, \"+\" being the system clipboard."
+  [register text]
+  (api/call (api/call-function :setreg register text)))
+
+(defn input
+  "Prompt for some input with input(), nil if it's cancelled or empty."
+  [prompt]
+  (let [text (api/call (api/call-function :input prompt))]
+    (when-not (= text "")
+      text)))
+
+(defn notify
+  "Display a message in the command line and keep it in :messages, so you see
+  it whichever buffer you're in."
+  [msg]
+  (api/call (api/command-output (str "echomsg '" (str/replace msg "'" "''") "'"))))
+
+(defn cwd
+  "Neovim's current working directory."
+  []
+  (api/call (api/call-function :getcwd)))
+
 (defn definition
   "Trigger built in go to definition."
   []
@@ -137,6 +217,25 @@
     [(api/command-output (str "edit " file))
      (api/win-set-cursor (:win ctx) {:row row, :col col})]))
 
+(defn set-quickfix
+  "Replace the quickfix list with the maps of :filename, :lnum, :col and :text
+  then open it."
+  [title entries]
+  (api/call-batch
+    [(api/call-function :setqflist [] " " {"title" title
+                                           "items" (mapv util/kw->snake-map entries)})
+     (api/command-output "copen")]))
+
+(defn add-to-quickfix
+  "Add the entries to the quickfix list if it has this title already,
+  otherwise start a new list. The quickfix window isn't opened so we don't
+  steal focus part way through an evaluation."
+  [title entries]
+  (let [current (api/call (api/call-function :getqflist {"title" 0}))]
+    (api/call (api/call-function :setqflist [] (if (= (get current "title") title) "a" " ")
+                                 {"title" title
+                                  "items" (mapv util/kw->snake-map entries)}))))
+
 (defn read-selection
   "Read the current selection into a string."
   []
@@ -163,10 +262,48 @@
               args)
        (api/call)))
 
-(defn append-lines [{:keys [trim-at buf win lines header]}]
-  (let [line-count (api/call (api/buf-line-count buf))
-        trim (if (> line-count trim-at)
-               (/ trim-at 2)
+(defn open-hud
+  "Briefly display some lines in the corner of the editor."
+  [{:keys [lines timeout hl]}]
+  (call-lua-function :open-hud lines timeout hl))
+
+(defn set-virtual-text
+  "Display some text at the end of the line in the buffer."
+  [{:keys [buf line text hl]}]
+  (call-lua-function :set-virtual-text buf line text hl))
+
+(defn place-test-signs
+  "Mark each test's definition with a pass or fail sign, replacing the signs
+  from the last run in those buffers. Files that aren't open are skipped."
+  [results]
+  (call-lua-function :place-test-signs (mapv util/kw->snake-map results)))
+
+(defn replace-lines
+  "Replace everything in the log buffer with the header and lines and scroll
+  the window, if there is one, to the bottom."
+  [{:keys [buf win lines header]}]
+  (let [lines (into [header] lines)]
+    (api/call-batch
+      [(api/buf-set-lines buf {:start 0, :end -1} lines)
+       (when win
+         (api/win-set-cursor win {:col 0, :row (count lines)}))])
+    nil))
+
+(defn append-lines
+  "Append the lines to the log buffer and scroll the window, if there is one,
+  to the bottom. It only scrolls when follow? is set and the cursor was
+  already on the last line, so you don't lose your place after scrolling up.
+  Once the buffer grows past trim-at lines the oldest are removed to bring it
+  back down to half that. With fold? the new lines are put into a closed fold.
+  A :highlight colours the first :width bytes of its first :lines new lines."
+  [{:keys [trim-at buf win lines header fold? follow? highlight]}]
+  (let [[line-count [row]] (api/call-batch
+                             [(api/buf-line-count buf)
+                              (when win
+                                (api/win-get-cursor win))])
+        trim (if (> (+ line-count (count lines)) trim-at)
+               (min (dec line-count)
+                    (- (+ line-count (count lines)) (quot trim-at 2)))
                0)
         new-line-count (+ line-count (count lines) (- trim))]
     (api/call-batch
@@ -180,5 +317,12 @@
 
        ;; Insert the new lines and scroll to the bottom.
        (api/buf-set-lines buf {:start -1, :end -1} lines)
-       (api/win-set-cursor win {:col 0, :row new-line-count})])
+       (when-let [{:keys [color width] :as highlight} highlight]
+         (api/execute-lua "return require('conjure').highlight_origin(...)"
+                          buf (- new-line-count (count lines)) (:lines highlight) width color))
+       (when (and win follow? (= row line-count))
+         (api/win-set-cursor win {:col 0, :row new-line-count}))
+       (when (and win fold?)
+         (api/call-function :win_execute win (str (inc (- new-line-count (count lines))) ","
+                                                  new-line-count "fold")))])
     nil))
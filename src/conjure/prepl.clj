@@ -2,87 +2,205 @@
   "Remote prepl connection management and selection."
   (:require [clojure.spec.alpha :as s]
             [clojure.core.async :as a]
-            [clojure.core.server :as server]
             [clojure.java.io :as io]
+            [clojure.edn :as edn]
+            [clojure.string :as str]
             [taoensso.timbre :as log]
             [conjure.util :as util]
             [conjure.ui :as ui]
-            [conjure.code :as code])
-  (:import [java.io PipedInputStream PipedOutputStream]))
+            [conjure.code :as code]
+            [conjure.socket :as socket]
+            [conjure.nrepl :as nrepl]
+            [conjure.unrepl :as unrepl])
+  (:import [java.io File PushbackReader]
+           [java.lang ProcessBuilder$Redirect]
+           [java.util.concurrent.locks ReentrantLock]))
 
 (s/def ::expr util/regexp?)
 (s/def ::tag keyword?)
 (s/def ::port number?)
+(s/def ::socket string?)
 (s/def ::lang #{:clj :cljs})
 (s/def ::host string?)
-(s/def ::new-conn (s/keys :req-un [::tag ::port]
-                          :opt-un [::expr ::lang ::host]))
+(s/def ::protocol #{:prepl :nrepl :repl :unrepl})
+(s/def ::blob string?)
+(s/def ::reconnect? boolean?)
+(s/def ::trust-store string?)
+(s/def ::trust-store-password string?)
+(s/def ::key-store string?)
+(s/def ::key-store-password string?)
+(s/def ::ssh string?)
+(s/def ::connect-timeout pos-int?)
+(s/def ::retries nat-int?)
+(s/def ::retry-delay nat-int?)
+(s/def ::lazy? boolean?)
+(s/def ::priority number?)
+(s/def ::command string?)
+(s/def ::build keyword?)
+(s/def ::wait pos-int?)
+(s/def ::eval-timeout pos-int?)
+(s/def ::bindings (s/map-of symbol? any?))
+(s/def ::test-runner #{:clojure.test :kaocha})
+(s/def ::output-limit pos-int?)
+(s/def ::label (s/and string? #(re-matches #"\S+" %)))
+(s/def ::color (s/and string? #(re-matches #"#[0-9a-fA-F]{6}" %)))
+(s/def ::tls (s/keys :opt-un [::trust-store ::trust-store-password
+                              ::key-store ::key-store-password]))
+(s/def ::new-conn (s/keys :req-un [::tag (or ::port ::socket)]
+                          :opt-un [::expr ::lang ::host ::protocol ::blob
+                                   ::reconnect? ::tls ::ssh
+                                   ::connect-timeout ::retries ::retry-delay
+                                   ::lazy? ::priority ::command ::build ::wait
+                                   ::eval-timeout ::bindings ::test-runner
+                                   ::output-limit ::label ::color]))
 
 (defonce ^:private conns! (atom {}))
+(def ^:private upgrade-code "(clojure.core.server/io-prepl)\n")
+(def ^:private base-reconnect-delay 500)
+(def ^:private max-reconnect-delay 30000)
+(def ^:private max-reconnect-attempts 10)
+(def ^:private heartbeat-interval 15000)
+(def ^:private heartbeat-timeout 5000)
+(def ^:private interrupt-timeout 10000)
+(def ^:private command-timeout 120000)
+(def ^:private default-output-limit 100000)
+(def ^:private output-batch-size 256)
+(def ^:private default-conn
+  {:host "127.0.0.1"
+   :lang :clj
+   :protocol :prepl
+   :reconnect? true
+   :priority 0})
 (def ^:private default-exprs
   {:clj #"\.cljc?$"
    :cljs #"\.clj(s|c)$"})
 
+(defn- kill!
+  "Destroy a process we started along with everything it started, build tools
+  tend to leave the actual JVM running otherwise."
+  [^Process process]
+  (when process
+    (run! #(.destroy ^ProcessHandle %) (iterator-seq (.iterator (.descendants process))))
+    (.destroy process)))
+
+(defn- close!
+  "Shut down the connection's channels and block until it's done."
+  [conn]
+  ;; read-chan is closed when the remote-prepl exits. This
+  ;; pattern of closing two here and then waiting for the
+  ;; read-chan to return a nil (which it will when closed)
+  ;; ensures that removal isn't complete until the remote-prepl is done.
+  ;; This prevents some weird race conditions with node connections.
+  ;; Lazy connections that were never opened don't have any channels.
+  (when-let [{:keys [eval-chan ret-chan read-chan]} (:chans conn)]
+    (a/close! eval-chan)
+    (a/close! ret-chan)
+    (loop []
+      (when-not (nil? (a/<!! read-chan))
+        (recur)))))
+
 (defn remove!
   "Remove the connection under the given tag. Shuts it down cleanly and blocks
-  until it's done."
+  until it's done, then stops the REPL process if we started it."
   [tag]
   (when-let [conn (get @conns! tag)]
     (log/info "Removing" tag)
     (ui/info "Removing" tag)
     (swap! conns! dissoc tag)
+    (close! conn)
 
-    ;; read-chan is closed when the remote-prepl exits. This
-    ;; pattern of closing two here and then waiting for the
-    ;; read-chan to return a nil (which it will when closed)
-    ;; ensures that removal isn't complete until the remote-prepl is done.
-    ;; This prevents some weird race conditions with node connections.
-    (let [{:keys [eval-chan ret-chan read-chan]} (:chans conn)]
-      (a/close! eval-chan)
-      (a/close! ret-chan)
-      (loop []
-        (when-not (nil? (a/<!! read-chan))
-          (recur))))))
+    (when-let [process (:process conn)]
+      (log/info "Stopping process for" tag)
+      (kill! process))))
 
 (defn remove-all! []
   (doseq [tag (keys @conns!)]
     (remove! tag)))
 
-(defn connect
+(defn kill-all!
+  "Destroy every process we started without waiting on the connections. Used
+  on the way out so nothing outlives Conjure."
+  []
+  (run! (comp kill! :process) (vals @conns!)))
+
+(defn- listening?
+  "Is something accepting connections on the connection's port or socket?"
+  [{:keys [host port socket]}]
+  (if socket
+    (.exists (io/file socket))
+    (util/port-open? host port)))
+
+(defn- spawn!
+  "Run the connection's :command through the shell and wait for it to start
+  listening. Output goes to a log file that's pointed out if it never comes up.
+  Returns the process or nil if that didn't happen in time."
+  [{:keys [tag command] :as conn}]
+  (let [log-file (File/createTempFile (str "conjure-" (name tag) "-") ".log")
+        _ (log/info "Starting" tag "with" command "logging to" (str log-file))
+        _ (ui/info "Starting" tag "-" command)
+        process (-> (ProcessBuilder. ^java.util.List ["sh" "-c" command])
+                    (.redirectErrorStream true)
+                    (.redirectOutput (ProcessBuilder$Redirect/to log-file))
+                    (.start))]
+    (util/wait-until command-timeout #(or (listening? conn) (not (.isAlive process))))
+    (if (and (.isAlive process) (listening? conn))
+      process
+      (do
+        (kill! process)
+        (ui/error tag "didn't start listening, see" (str log-file))
+        nil))))
+
+(defn- connect-prepl
   "Connect to a prepl and return channels to interact with it. When the eval
   channel closes it cascades through the system and eventually closes the read
   channel. We can use this fact to await the read channel's closure to know
-  when the closing is complete. Handy!"
-  [{:keys [tag host port]}]
+  when the closing is complete. Handy!
+
+  Plain socket REPLs (the :repl protocol) are upgraded into a prepl by
+  evaluating io-prepl before anything else is sent."
+  [{:keys [tag protocol] :as opts}]
   (let [[eval-chan read-chan] (repeatedly #(a/chan 32))
-        input (PipedInputStream.)
-        output (PipedOutputStream. input)]
+        writer! (promise)]
 
     (util/thread
       "reader loop"
-      (with-open [reader (io/reader input)]
-        (try
-          (log/info "Connecting through remote-prepl" tag)
-          (server/remote-prepl
-            host port reader
-            (fn [out]
-              (log/trace "Read from remote-prepl" tag "-" out)
-              (a/>!! read-chan out))
-            :valf identity)
+      (try
+        (log/info "Connecting to prepl" tag)
+        (let [{:keys [input output close]} (socket/open opts)
+              reader (PushbackReader. (io/reader input))
+              eof (Object.)]
+          (deliver writer! {:writer (io/writer output), :close close})
+          (loop []
+            (let [out (read reader false eof)]
+              (when-not (identical? out eof)
+                (log/trace "Read from prepl" tag "-" out)
 
-          (catch Exception e
-            (log/error "Error from remote-prepl:" e)
-            (ui/error "Error from" tag e))
+                ;; Anything that isn't a map is the socket REPL's prompt from
+                ;; before the upgrade, something like user=>.
+                (when (map? out)
+                  (a/>!! read-chan out))
+                (recur)))))
 
-          (finally
-            (log/trace "Exited remote-prepl, cleaning up" tag)
-            (a/close! read-chan)
-            (remove! tag)))))
+        (catch Exception e
+          (log/error "Error from prepl:" e)
+
+          ;; Once we're connected errors are usually just the socket closing.
+          (when-not (realized? writer!)
+            (ui/error "Error from" tag e)))
+
+        (finally
+          (log/trace "Exited prepl reader, cleaning up" tag)
+          (deliver writer! nil)
+          (a/close! read-chan))))
 
     (util/thread
       "writer loop"
-      (with-open [writer (io/writer output)]
+      (when-let [{:keys [writer close]} @writer!]
         (try
+          (when (= protocol :repl)
+            (log/trace "Upgrading socket REPL to prepl" tag)
+            (util/write writer upgrade-code))
+
           (loop []
             (when-let [code (a/<!! eval-chan)]
               (log/trace "Writing to tag:" tag "-" code)
@@ -94,71 +212,647 @@
 
           (finally
             (log/trace "Exited eval-chan loop, cleaning up" tag)
-            (util/write writer ":repl/quit\n")))))
+            (try
+              (util/write writer ":repl/quit\n")
+              (finally
+                (close)))))))
 
     {:eval-chan eval-chan
      :read-chan read-chan}))
 
-(defn add!
-  "Remove any existing connection under :tag then create a new connection."
-  [{:keys [tag lang expr host port]
-    :or {host "127.0.0.1"
-         lang :clj}}]
+(defn- via-shadow
+  "Put a channel in front of the eval-chan that wraps everything in a
+  shadow-cljs eval for the build. Closing it closes the original too."
+  [{:keys [eval-chan] :as chans} build]
+  (let [cljs-chan (a/chan 32 (map #(code/shadow-eval-str build %)))]
+    (a/pipe cljs-chan eval-chan)
+    (assoc chans :eval-chan cljs-chan)))
 
-  (remove! tag)
+(defn connect
+  "Connect using the given protocol, every protocol returns the same eval and
+  read channels so nothing else needs to know which one is in use. Connections
+  with a shadow-cljs :build talk to the Clojure side of shadow-cljs and have
+  their ClojureScript sent through to that build."
+  [{:keys [protocol build] :as opts}]
+  (cond-> (case protocol
+            (:prepl :repl) (connect-prepl opts)
+            :nrepl (nrepl/connect opts)
+            :unrepl (unrepl/connect opts))
+    build (via-shadow build)))
+
+(defn- read-ret
+  "Wait for the next :ret on the read-chan, anything printed before it is
+  dropped. Returns nil if the read-chan closes first."
+  [read-chan]
+  (loop []
+    (when-let [out (a/<!! read-chan)]
+      (if (= (:tag out) :ret)
+        out
+        (do
+          (log/trace "Dropping output while connecting:" out)
+          (recur))))))
 
-  (log/info "Adding" tag host port)
-  (ui/info "Adding" tag)
+(defn- detect
+  "Work out which flavour of Clojure is on the other end. Babashka gets a
+  slightly different prelude and a few different implementations in
+  conjure.code since it doesn't have everything the JVM does."
+  [{:keys [lang protocol chans] :as conn}]
+  (if (= lang :clj)
+    (let [{:keys [eval-chan read-chan]} chans]
+      (a/>!! eval-chan code/bb-detect-str)
+      (when-let [{:keys [val]} (read-ret read-chan)]
+        (log/trace "Babashka version:" val)
+        (let [conn (assoc conn :bb? (not= val "nil"))]
+          ;; Remember which thread evaluates our code so interrupt! can stop
+          ;; it from another connection. nREPL has its own interrupt op.
+          (if (or (:bb? conn) (= protocol :nrepl))
+            conn
+            (do
+              (a/>!! eval-chan code/thread-id-str)
+              (when-let [{:keys [val]} (read-ret read-chan)]
+                (assoc conn :thread-id (edn/read-string val))))))))
+    conn))
 
+(defn- open!
+  "Connect and send the prelude. Returns the connection with its channels
+  attached or nil if we couldn't connect."
+  [conn]
   (let [ret-chan (a/chan 32)
-        conn {:tag tag
-              :lang lang
-              :host host
-              :port port
-              :expr (or expr (get default-exprs lang))
-              :chans (merge
-                       {:ret-chan ret-chan}
-                       (connect {:tag tag
-                                 :host host
-                                 :port port}))}
-        prelude (code/prelude-str {:lang lang})]
-
-    (swap! conns! assoc tag conn)
-
-    (log/trace "Sending prelude:" prelude)
-    (a/>!! (get-in conn [:chans :eval-chan]) prelude)
-    (log/trace "Prelude result:" (a/<!! (get-in conn [:chans :read-chan])))
+        conn (assoc conn
+                    :lock (ReentrantLock. true)
+                    :chans (merge {:ret-chan ret-chan} (connect conn)))
+        {:keys [eval-chan read-chan]} (:chans conn)]
+
+    ;; A closed read-chan before the prelude returns means we never connected.
+    (if-let [conn (when-let [conn (detect conn)]
+                    (let [prelude (code/prelude-str conn)]
+                      (log/trace "Sending prelude:" prelude)
+                      (a/>!! eval-chan prelude)
+                      (when-let [result (read-ret read-chan)]
+                        (log/trace "Prelude result:" result)
+                        (if (and (= (:protocol conn) :nrepl) (= (:lang conn) :clj))
+                          (do
+                            (a/>!! eval-chan (code/nrepl-tap-str conn))
+                            (when (read-ret read-chan)
+                              conn))
+                          conn))))]
+      (assoc conn
+             :connected-at (System/currentTimeMillis)
+             :evals 0)
+      (do
+        (a/close! eval-chan)
+        (a/close! ret-chan)
+        nil))))
+
+(defn- backoff
+  "How long to wait before the given reconnection attempt, doubles each time."
+  [attempt]
+  (min max-reconnect-delay
+       (* base-reconnect-delay (long (Math/pow 2 (dec attempt))))))
+
+(defn- find-by-chans [conns chans]
+  (some (fn [conn] (when (= chans (:chans conn)) conn)) (vals conns)))
+
+(defn- current
+  "Find the registered connection that owns the given channels. Tags can change
+  (see rename!) so this is how long running loops find themselves again.
+  Returns nil once the connection has been removed or replaced."
+  [chans]
+  (find-by-chans @conns! chans))
+
+(defn- update-current!
+  "Update the connection that owns the channels if it's still registered."
+  [chans f]
+  (swap! conns!
+         (fn [conns]
+           (if-let [{:keys [tag]} (find-by-chans conns chans)]
+             (update conns tag f)
+             conns))))
+
+(defn- send-stdin!
+  "Send a line of input to whatever is reading stdin on the connection. prepl
+  reads *in* from the socket itself so the text goes straight down the eval
+  channel, nREPL has an op for it."
+  [{:keys [chans]} text]
+  (if-let [stdin-chan (:stdin-chan chans)]
+    (a/>!! stdin-chan (str text "\n"))
+    (a/>!! (:eval-chan chans) (str text "\n"))))
+
+(declare handle!)
+
+(defn- reconnect!
+  "Try to re-open a connection that dropped without being removed. Gives up
+  after a few attempts or as soon as the connection is removed or replaced."
+  [{:keys [tag chans]}]
+  (log/info "Lost connection to" tag)
+  (ui/info "Lost connection to" tag "- reconnecting")
+  (update-current! chans #(assoc % :reconnecting? true))
+
+  (loop [attempt 1]
+    (when (current chans)
+      (Thread/sleep (backoff attempt))
+      (when-let [{:keys [tag] :as conn} (current chans)]
+        (log/info "Reconnecting to" tag "attempt" attempt)
+        (if-let [new-conn (open! (dissoc conn :chans :lock :stale? :reconnecting?))]
+          (if-let [{:keys [tag]} (current chans)]
+            (let [new-conn (assoc new-conn :tag tag)]
+              (swap! conns! assoc tag new-conn)
+              (handle! new-conn)
+              (ui/info "Reconnected to" tag))
+            (a/close! (get-in new-conn [:chans :eval-chan])))
+          (if (< attempt max-reconnect-attempts)
+            (recur (inc attempt))
+            (do
+              (ui/error "Giving up reconnecting to" tag "after" attempt "attempts")
+              (remove! tag))))))))
+
+(defn- abandon!
+  "Drop a connection that can't be trusted to answer in order any more. The
+  read-chan handler sees it close and reconnects as if it had gone away."
+  [{:keys [chans]}]
+  (when-let [{:keys [tag]} (current chans)]
+    (log/warn "Abandoning" tag)
+    (ui/error tag "stopped answering, reconnecting")
+    (update-current! chans #(assoc % :stale? true)))
+  (a/close! (:eval-chan chans))
+  (a/close! (:ret-chan chans)))
+
+(defn- discard-ret!
+  "Wait for the :ret we've already given up on and throw it away, it'd be taken
+  as the next eval's result otherwise. The connection is abandoned if it
+  doesn't turn up in time."
+  [{:keys [chans] :as conn} timeout]
+  (let [ret-chan (:ret-chan chans)
+        [ret port] (a/alts!! [ret-chan (a/timeout timeout)])]
+    (if (and ret (= port ret-chan))
+      (do
+        (log/trace "Discarding abandoned result:" ret)
+        ret)
+      (abandon! conn))))
+
+(defn exclusively
+  "Call f while holding the connection's eval lock. Whoever holds the lock owns
+  the next value on the ret-chan, anything somehow left over from before is
+  thrown away before we start.
+
+  The lock is fair so it doubles as the connection's eval queue: evals run one
+  at a time in the order they asked for it and nothing is sent until the
+  previous result is back, which keeps output and results in order. The queue
+  length is shown by status."
+  [{:keys [^ReentrantLock lock chans]} f]
+  (.lock lock)
+  (try
+    (loop []
+      (when-let [stray (a/poll! (:ret-chan chans))]
+        (log/warn "Discarding stray result:" stray)
+        (recur)))
+    (f)
+    (finally
+      (.unlock lock))))
+
+(defn count-eval!
+  "Count an evaluation the user asked for towards the connection's metrics."
+  [{:keys [chans]}]
+  (update-current! chans #(update % :evals inc)))
+
+(defn- beat!
+  "Send a heartbeat and wait for it to come back. If it doesn't come back in
+  time we mark the connection as stale, half open sockets would otherwise
+  swallow evals without a word. We hold on to the lock until the heartbeat's
+  :ret turns up so it's never taken as an eval's result, abandoning the
+  connection if it takes too long. Does nothing if the connection is busy."
+  [{:keys [^ReentrantLock lock chans] :as conn}]
+  (when (.tryLock lock)
+    (try
+      (a/>!! (:eval-chan chans) (code/heartbeat-str conn))
+      (let [start (System/currentTimeMillis)
+            ret-chan (:ret-chan chans)
+            [resp] (a/alts!! [ret-chan (a/timeout heartbeat-timeout)])
+            resp (or resp
+                     (do
+                       (when-let [{:keys [tag]} (current chans)]
+                         (log/warn "Heartbeat timed out for" tag)
+                         (ui/error tag "isn't responding, evaluations may never return"))
+                       (update-current! chans #(assoc % :stale? true))
+                       (discard-ret! conn interrupt-timeout)))]
+        (when resp
+          (update-current! chans #(assoc %
+                                         :stale? false
+                                         :latency (- (System/currentTimeMillis) start)))))
+      (finally
+        (.unlock lock)))))
+
+(defn- heartbeat!
+  "Periodically send a heartbeat to the connection while it's registered."
+  [{:keys [chans] :as conn}]
+  (util/thread
+    "heartbeat"
+    (loop []
+      (Thread/sleep heartbeat-interval)
+      (when (current chans)
+        (beat! conn)
+        (recur)))))
 
+(defn- output? [out]
+  (contains? #{:out :err} (:tag out)))
+
+(defn- coalesce-output
+  "Merge any output that's already waiting on the read-chan into this one so a
+  busy println loop becomes a handful of writes to Neovim instead of one per
+  line. Returns the merged outputs along with the first message we took that
+  wasn't output, if there was one."
+  [read-chan out]
+  (loop [acc [out]]
+    (let [next-out (when (< (count acc) output-batch-size)
+                     (a/poll! read-chan))]
+      (if (output? next-out)
+        (recur (conj acc next-out))
+        [(for [outs (partition-by :tag acc)]
+           {:tag (:tag (first outs))
+            :val (str/join (map :val outs))})
+         next-out]))))
+
+(defn- flush-dropped!
+  "Tell the user how much output we dropped since the last time we checked."
+  [tag throttle!]
+  (let [{:keys [dropped]} @throttle!]
+    (when (pos? dropped)
+      (swap! throttle! assoc :dropped 0)
+      (ui/info "Dropped" dropped "characters of output from" tag))))
+
+(defn- throttle
+  "Count output against the connection's :output-limit, in characters a second.
+  Anything over the limit is dropped (and counted) rather than freezing Neovim,
+  we warn once when it starts and say how much was lost when it calms down."
+  [{:keys [tag output-limit] :or {output-limit default-output-limit}} throttle! outs]
+  (let [now (System/currentTimeMillis)
+        size (reduce + (map (comp count :val) outs))]
+    (when (>= (- now (:start @throttle!)) 1000)
+      (let [dropping? (pos? (:dropped @throttle!))]
+        (flush-dropped! tag throttle!)
+        (swap! throttle! assoc :start now, :chars 0, :dropping? dropping?)))
+    (let [{:keys [chars dropping?]} (swap! throttle! update :chars + size)]
+      (if (> chars output-limit)
+        (do
+          (when-not dropping?
+            (ui/error tag "is printing more than" output-limit
+                      "characters a second, dropping output until it slows down."
+                      "ConjureInterrupt will stop it.")
+            (swap! throttle! assoc :dropping? true))
+          (swap! throttle! update :dropped + size)
+          nil)
+        outs))))
+
+(defn- handle!
+  "Handle everything coming out of the read-chan. :ret values go to the
+  ret-chan for whoever is waiting on them, everything else is displayed.
+  Output is coalesced and throttled so runaway printing can't drown Neovim."
+  [{:keys [tag chans] :as conn}]
+  (let [{:keys [read-chan ret-chan]} chans
+        throttle! (atom {:start 0, :chars 0, :dropped 0, :dropping? false})]
     (util/thread
       "read-chan handler"
-      (loop []
-        (when-let [out (a/<!! (get-in conn [:chans :read-chan]))]
-          (log/trace "Read value from" (:tag conn) "-" out)
+      (loop [out (a/<!! read-chan)]
+        (when out
+          (log/trace "Read value from" tag "-" out)
           (let [out (cond-> out
                       (contains? #{:tap :ret} (:tag out))
-                      (update :val code/parse-code))]
-            (if (= (:tag out) :ret)
-              (a/>!! ret-chan out)
-              (ui/result {:conn conn, :resp out})))
-          (recur))))))
+                      (update :val code/parse-code))
+                conn (or (current chans) conn)]
+            (if (output? out)
+              (let [[outs next-out] (coalesce-output read-chan out)]
+                (doseq [out (throttle conn throttle! outs)]
+                  (ui/result {:conn conn, :resp out}))
+                (recur (or next-out (a/<!! read-chan))))
+              (do
+                (case (:tag out)
+                  :ret (do
+                         (flush-dropped! tag throttle!)
+                         (a/>!! ret-chan out))
+                  :need-input (util/thread
+                                "stdin prompt"
+                                (when-let [text (ui/prompt (str (name tag) " stdin> "))]
+                                  (send-stdin! conn text)))
+                  (ui/result {:conn conn, :resp out}))
+                (recur (a/<!! read-chan)))))))
+
+      ;; The read-chan only closes when the connection is gone. If we're still
+      ;; registered then nobody asked for this and we should try to reconnect.
+      ;; Make sure we don't touch a newer connection that's taken our tag.
+      (when-let [{:keys [tag reconnect?] :as conn} (current chans)]
+        (if reconnect?
+          (reconnect! conn)
+          (remove! tag))))
+
+    (heartbeat! conn)))
+
+(defn- await!
+  "Wait up to :wait milliseconds for the connection's port or socket to start
+  accepting connections so you can add a REPL that's still booting. Always true
+  without :wait. SSH connections aren't checked since their port is on the
+  other side of the tunnel."
+  [{:keys [tag wait ssh] :as conn}]
+  (if (or (nil? wait) ssh (listening? conn))
+    true
+    (do
+      (ui/info "Waiting for" tag "to start listening")
+      (or (util/wait-until wait #(listening? conn))
+          (do
+            (ui/error tag "still isn't listening after" wait "ms")
+            false)))))
+
+(defn- start!
+  "Run the connection's :command if it has one. Returns the connection with the
+  process attached, or nil if it never started listening."
+  [conn]
+  (if (:command conn)
+    (when-let [process (spawn! conn)]
+      (assoc conn :process process))
+    conn))
+
+(defn add!
+  "Remove any existing connection under :tag then create a new connection."
+  [{:keys [tag] :as new-conn}]
+  (remove! tag)
+
+  (let [conn (merge default-conn new-conn)
+        conn (update conn :expr #(or % (get default-exprs (:lang conn))))]
+    (log/info "Adding" tag (select-keys conn [:host :port :socket]))
+    (ui/info "Adding" tag)
+
+    (if (:lazy? conn)
+      (swap! conns! assoc tag conn)
+      (when-let [conn (start! conn)]
+        (if-let [opened (and (await! conn) (open! conn))]
+          (do
+            (swap! conns! assoc tag opened)
+            (handle! opened))
+          (kill! (:process conn)))))))
+
+(defn reconnect-all!
+  "Drop and re-open every open connection with the same configuration, handy
+  after your machine wakes up. Processes started with :command keep running
+  and lazy connections are left alone until they're next used."
+  []
+  (doseq [{:keys [tag lazy?] :as conn} (vals @conns!)]
+    (when-not lazy?
+      (log/info "Reconnecting" tag)
+      (ui/info "Reconnecting" tag)
+      (swap! conns! dissoc tag)
+      (close! conn)
+      (if-let [opened (open! (dissoc conn :stale? :reconnecting? :latency))]
+        (do
+          (swap! conns! assoc tag opened)
+          (handle! opened)
+          (ui/info "Reconnected to" tag))
+        (do
+          (ui/error "Couldn't reconnect to" tag)
+          (kill! (:process conn)))))))
+
+(defn- interrupt-via-control!
+  "Open a second connection just to stop the thread the first one evaluates on.
+  Returns true if the thread was found."
+  [{:keys [thread-id] :as conn}]
+  (let [{:keys [eval-chan read-chan]} (connect (dissoc conn :build))]
+    (try
+      (a/>!! eval-chan (code/interrupt-str thread-id))
+      (let [[resp] (a/alts!! [(a/thread (read-ret read-chan))
+                              (a/timeout heartbeat-timeout)])]
+        (= (:val resp) "true"))
+      (finally
+        (a/close! eval-chan)))))
+
+(defn interrupt!
+  "Stop whatever the connection is evaluating right now. The eval that was
+  running returns an error like any other so the connection carries on."
+  [tag]
+  (if-let [{:keys [chans thread-id] :as conn} (get @conns! tag)]
+    (do
+      (log/info "Interrupting" tag)
+      (cond
+        (:interrupt-chan chans)
+        (do
+          (a/>!! (:interrupt-chan chans) :interrupt)
+          (ui/info "Interrupted" tag))
+
+        (nil? thread-id)
+        (ui/error tag "doesn't support interrupting evaluations")
+
+        (interrupt-via-control! conn)
+        (ui/info "Interrupted" tag)
+
+        :else
+        (ui/error "Couldn't interrupt" tag)))
+    (ui/error "No connection tagged" tag)))
+
+(defn stdin!
+  "Send a line of input to the connection. The text is only sent if it's busy
+  evaluating something, otherwise a prepl would treat it as more code."
+  [tag text]
+  (if-let [{:keys [^ReentrantLock lock] :as conn} (get @conns! tag)]
+    (if (and lock (.isLocked lock))
+      (send-stdin! conn text)
+      (ui/error tag "isn't evaluating anything that could be waiting for input"))
+    (ui/error "No connection tagged" tag)))
+
+(defn await-ret
+  "Wait for the next :ret from the connection. Once its :eval-timeout passes
+  we interrupt the eval and return an error instead. The caller still holds the
+  lock while we wait for the interrupted eval's own :ret to discard it, so it
+  can't be mistaken for the result of whatever runs next."
+  [{:keys [tag eval-timeout chans] :as conn}]
+  (let [ret-chan (:ret-chan chans)]
+    (if-not eval-timeout
+      (a/<!! ret-chan)
+      (let [[ret port] (a/alts!! [ret-chan (a/timeout eval-timeout)])]
+        (if (= port ret-chan)
+          ret
+          (do
+            (ui/error tag "took longer than" (str eval-timeout "ms") "- interrupting")
+            (interrupt! tag)
+            (discard-ret! conn interrupt-timeout)
+            {:tag :ret
+             :val [:error {:cause (str "Evaluation timed out after " eval-timeout "ms")}]}))))))
+
+(defn- wake!
+  "Open a lazy connection now that somebody needs it, starting its :command
+  first if it has one. Returns the opened connection, or nil if we couldn't
+  connect, leaving it lazy for next time."
+  [{:keys [tag]}]
+  (locking conns!
+    (let [conn (get @conns! tag)]
+      (if-not (:lazy? conn)
+        conn
+        (do
+          (log/info "Waking lazy connection" tag)
+          (ui/info "Connecting" tag)
+          (when-let [conn (start! (dissoc conn :lazy?))]
+            (if-let [opened (open! conn)]
+              (do
+                (swap! conns! assoc tag opened)
+                (handle! opened)
+                opened)
+              (do
+                (kill! (:process conn))
+                nil))))))))
+
+(defn rename!
+  "Move a connection to a new tag without disconnecting it. Returns true if it
+  was moved."
+  [from to]
+  (let [problem! (volatile! nil)]
+    (swap! conns! (fn [conns]
+                    (vreset! problem! (cond
+                                        (not (contains? conns from))
+                                        ["No connection tagged" from]
+
+                                        (contains? conns to)
+                                        ["There's already a connection tagged" to]))
+                    (if @problem!
+                      conns
+                      (-> conns
+                          (dissoc from)
+                          (assoc to (assoc (get conns from) :tag to))))))
+    (if-let [problem @problem!]
+      (do
+        (apply ui/error problem)
+        false)
+      (do
+        (log/info "Renamed" from "to" to)
+        (ui/info "Renamed" from "to" to)
+        true))))
+
+(defn set-bindings!
+  "Replace the dynamic bindings wrapped around every eval on the connection."
+  [tag bindings]
+  (if (contains? @conns! tag)
+    (do
+      (swap! conns! assoc-in [tag :bindings] bindings)
+      (ui/info "Bindings for" tag "set to" (pr-str bindings)))
+    (ui/error "No connection tagged" tag)))
+
+(defn set-ns!
+  "Set the namespace evals on the connection fall back to when the buffer
+  doesn't have an ns form of its own."
+  [tag ns]
+  (if (contains? @conns! tag)
+    (do
+      (swap! conns! assoc-in [tag :ns] ns)
+      (ui/info "Namespace for" tag "set to" ns))
+    (ui/error "No connection tagged" tag)))
+
+(defn- port-file->conn
+  "Turn a port file into a new connection map, returns nil if the file doesn't
+  look like a port file we understand."
+  [file]
+  (let [file-name (.getName (io/file file))
+        shadow? (= (.getName (.getParentFile (io/file file))) ".shadow-cljs")
+        conn (cond
+               (= file-name ".prepl-port") {:tag :prepl, :protocol :prepl}
+               (= file-name ".nrepl-port") {:tag :nrepl, :protocol :nrepl}
+               (and shadow? (str/ends-with? file-name ".port"))
+               (let [kind (str/replace file-name #"\.port$" "")]
+                 {:tag (keyword (str "shadow-" kind))
+                  :protocol (if (str/includes? kind "nrepl") :nrepl :repl)}))]
+    (when conn
+      (try
+        (let [port (edn/read-string (str/trim (slurp file)))]
+          (when (number? port)
+            (assoc conn :port port)))
+        (catch Exception e
+          (log/warn "Couldn't read port file" (str file) e))))))
+
+(defn discover
+  "Walk up from the given path looking for port files such as .prepl-port,
+  .nrepl-port or .shadow-cljs/*.port. Returns new connection maps for every
+  port file in the closest directory that contains any."
+  [path]
+  (loop [dir (io/file path)]
+    (when dir
+      (let [shadow-dir (io/file dir ".shadow-cljs")
+            candidates (concat
+                         [(io/file dir ".prepl-port")
+                          (io/file dir ".nrepl-port")]
+                         (when (.isDirectory shadow-dir)
+                           (sort (.listFiles shadow-dir))))
+            found (->> candidates
+                       (filter #(.isFile ^java.io.File %))
+                       (keep port-file->conn)
+                       (seq))]
+        (or found (recur (.getParentFile dir)))))))
+
+(defn route
+  "Every connection who's :expr matches the path, highest :priority first and
+  then by tag so the order is always the same."
+  [conns path]
+  (->> conns
+       (filter
+         (fn [{:keys [expr reconnecting?]}]
+           (and (not reconnecting?) (re-find expr path))))
+       (sort-by (juxt (comp - :priority) (comp str :tag)))))
 
 (defn conns
   "Without a path it'll return all current connections. With a path it finds
-  any connection who's :expr matches that string."
-  ([] (vals @conns!))
+  any connection who's :expr matches that string, opening any lazy connections
+  along the way."
+  ([] (sort-by (comp str :tag) (vals @conns!)))
   ([path]
-   (->> (conns)
-        (filter
-          (fn [{:keys [expr]}]
-            (re-find expr path)))
+   (->> (route (conns) path)
+        (keep
+          (fn [conn]
+            (if (:lazy? conn)
+              (wake! conn)
+              conn)))
         (seq))))
 
+(defn conn
+  "The connection under the tag regardless of what it matches, opening it if
+  it's lazy. Displays an error and returns nil if there isn't one."
+  [tag]
+  (if-let [conn (get @conns! tag)]
+    (if (:lazy? conn)
+      (wake! conn)
+      conn)
+    (ui/error "No connection tagged" tag)))
+
+(defn show-route
+  "Display the connections that an evaluation from the path would go to, in
+  the order they'd be used. Doesn't open any lazy connections."
+  [path]
+  (let [routed (route (conns) path)]
+    (ui/info (util/join-lines
+               (into [(str (util/count-str routed "connection") " for " path)]
+                     (for [{:keys [tag priority]} routed]
+                       (str tag " (priority " priority ")")))))))
+
+(defn- metrics-str
+  "Uptime, evals served, queued evals and the last heartbeat round trip for an
+  open connection."
+  [{:keys [connected-at evals latency ^ReentrantLock lock]}]
+  (when connected-at
+    (str "up " (util/duration-str (- (System/currentTimeMillis) connected-at))
+         ", " (util/count-str evals "eval")
+         ", " (.getQueueLength lock) " queued"
+         ", " (if latency (str latency "ms ping") "no ping yet"))))
+
 (defn status
   "Display the current status of the connections. This counts and lists with
-  some connection information."
+  some connection information and metrics."
   []
   (let [conns (conns)
         intro (util/count-str conns "connection")
-        conn-strs (for [{:keys [tag host port expr lang]} conns]
-                    (str tag " @ " host ":" port " for " (pr-str expr) " (" lang ")"))]
+        conn-strs (for [{:keys [tag host port socket ssh tls expr lang protocol
+                                command bb? build reconnecting? stale? lazy?]
+                         :as conn} conns]
+                    (str tag " @ " (or socket (str host ":" port))
+                         (when ssh (str " via " ssh))
+                         " for " (pr-str expr)
+                         " (" lang (when bb? " on Babashka") (when build (str " build " build))
+                         " over " (name protocol) (when tls " with TLS") ")"
+                         (when command " [started by Conjure]")
+                         (when lazy? " [lazy]")
+                         (when reconnecting? " [reconnecting]")
+                         (when stale? " [stale]")
+                         (when-let [metrics (metrics-str conn)]
+                           (str "\n  " metrics))))]
     (ui/info (util/join-lines (into [intro] conn-strs)))))
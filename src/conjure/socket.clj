@@ -0,0 +1,198 @@
+(ns conjure.socket
+  "Opening the sockets that every protocol talks over."
+  (:require [clojure.java.io :as io]
+            [taoensso.timbre :as log]
+            [conjure.util :as util])
+  (:import [java.io InputStream OutputStream]
+           [java.net Socket InetSocketAddress SocketAddress ProtocolFamily]
+           [java.nio ByteBuffer]
+           [java.nio.channels SocketChannel]
+           [java.security KeyStore]
+           [javax.net.ssl SSLContext SSLSocket TrustManagerFactory KeyManagerFactory]
+           [java.lang ProcessBuilder$Redirect]))
+
+(def ^:private tunnel-timeout 15000)
+
+(defn- channel-streams
+  "Streams on top of a blocking SocketChannel. We can't use Channels/newInputStream
+  and friends here since they share a lock, a blocked read would block every
+  write too."
+  [^SocketChannel ch]
+  {:input (proxy [InputStream] []
+            (read
+              ([]
+               (let [buf (ByteBuffer/allocate 1)]
+                 (if (= (.read ch buf) -1)
+                   -1
+                   (bit-and (.get buf 0) 0xff))))
+              ([^bytes b]
+               (.read ch (ByteBuffer/wrap b)))
+              ([^bytes b off len]
+               (if (zero? len)
+                 0
+                 (.read ch (ByteBuffer/wrap b off len))))))
+
+   :output (proxy [OutputStream] []
+             (write
+               ([b]
+                (let [buf (if (integer? b)
+                            (ByteBuffer/wrap (byte-array [(unchecked-byte b)]))
+                            (ByteBuffer/wrap ^bytes b))]
+                  (while (.hasRemaining buf)
+                    (.write ch buf))))
+               ([^bytes b off len]
+                (let [buf (ByteBuffer/wrap b off len)]
+                  (while (.hasRemaining buf)
+                    (.write ch buf))))))})
+
+(defn- static-call
+  "Call a public static method by name, for classes and methods that might not
+  exist on the JVM we're running on."
+  [^Class klass method-name param-types & args]
+  (.invoke (.getMethod klass method-name (into-array Class param-types))
+           nil
+           (object-array args)))
+
+(defn- open-unix
+  "Unix domain sockets arrived in Java 16 so everything involved is looked up
+  when we need it, importing it would stop this namespace loading at all on
+  older JVMs."
+  [path]
+  (let [address-class (try
+                        (Class/forName "java.net.UnixDomainSocketAddress")
+                        (catch ClassNotFoundException _
+                          (throw (ex-info (str "Unix sockets need Java 16+, this is Java "
+                                               (System/getProperty "java.version"))
+                                          {:socket path}))))
+        unix (Enum/valueOf java.net.StandardProtocolFamily "UNIX")
+        ch ^SocketChannel (static-call SocketChannel "open" [ProtocolFamily] unix)]
+    (.connect ch ^SocketAddress (static-call address-class "of" [String] path))
+    (merge (channel-streams ch)
+           {:close #(.close ch)})))
+
+(defn- tcp-socket ^Socket [host port timeout]
+  (doto (Socket.)
+    (.connect (InetSocketAddress. ^String host (int port)) (int timeout))))
+
+(defn- open-tcp [host port timeout]
+  (let [socket (tcp-socket host port timeout)]
+    {:input (.getInputStream socket)
+     :output (.getOutputStream socket)
+     :close #(.close socket)}))
+
+(defn- load-store [path password]
+  (with-open [input (io/input-stream path)]
+    (doto (KeyStore/getInstance (KeyStore/getDefaultType))
+      (.load input (some-> ^String password (.toCharArray))))))
+
+(defn- ssl-context
+  "Build an SSLContext from the optional trust store (for the server's CA) and
+  key store (for client certificates). Anything left out falls back to the JVM
+  defaults."
+  [{:keys [trust-store trust-store-password key-store key-store-password]}]
+  (let [tmf (when trust-store
+              (doto (TrustManagerFactory/getInstance (TrustManagerFactory/getDefaultAlgorithm))
+                (.init ^KeyStore (load-store trust-store trust-store-password))))
+        kmf (when key-store
+              (doto (KeyManagerFactory/getInstance (KeyManagerFactory/getDefaultAlgorithm))
+                (.init ^KeyStore (load-store key-store key-store-password)
+                       (.toCharArray ^String (or key-store-password "")))))]
+    (doto (SSLContext/getInstance "TLS")
+      (.init (some-> ^KeyManagerFactory kmf (.getKeyManagers))
+             (some-> ^TrustManagerFactory tmf (.getTrustManagers))
+             nil))))
+
+(defn- open-tls [host port timeout tls]
+  (let [socket ^SSLSocket (.createSocket (.getSocketFactory ^SSLContext (ssl-context tls))
+                                         (tcp-socket host port timeout)
+                                         ^String host
+                                         (int port)
+                                         true)
+        params (doto (.getSSLParameters socket)
+                 (.setEndpointIdentificationAlgorithm "HTTPS"))]
+    (.setSSLParameters socket params)
+    (.startHandshake socket)
+    {:input (.getInputStream socket)
+     :output (.getOutputStream socket)
+     :close #(.close socket)}))
+
+(declare open*)
+
+(defn- open-ssh
+  "Forward a free local port to the host and port as seen from the SSH
+  destination, then open the connection through that. ssh's stderr goes
+  to ours so any problems end up in front of the user. The tunnel is torn down
+  again if the connection through it fails."
+  [{:keys [host port ssh] :as opts}]
+  (let [local-port (util/free-port)
+        forward (str local-port ":" host ":" port)
+        _ (log/info "Opening SSH tunnel" forward "via" ssh)
+        process (-> (ProcessBuilder. ^java.util.List
+                                     ["ssh" "-N"
+                                      "-o" "BatchMode=yes"
+                                      "-o" "ExitOnForwardFailure=yes"
+                                      "-L" forward
+                                      ssh])
+                    (.redirectOutput ProcessBuilder$Redirect/DISCARD)
+                    (.redirectError ProcessBuilder$Redirect/INHERIT)
+                    (.start))]
+    (if (util/wait-for-port {:host "127.0.0.1"
+                             :port local-port
+                             :timeout tunnel-timeout})
+      (let [{:keys [close] :as conn} (try
+                                       (open* (-> opts
+                                                  (dissoc :ssh)
+                                                  (assoc :host "127.0.0.1"
+                                                         :port local-port)))
+                                       (catch Exception e
+                                         (.destroy process)
+                                         (throw e)))]
+        (assoc conn :close (fn []
+                             (try
+                               (close)
+                               (finally
+                                 (.destroy process))))))
+      (do
+        (.destroy process)
+        (throw (ex-info "SSH tunnel didn't open in time" {:ssh ssh, :forward forward}))))))
+
+(defn- open* [{:keys [host port socket tls ssh connect-timeout] :as opts}]
+  (cond
+    socket (open-unix socket)
+    ssh (open-ssh opts)
+    tls (open-tls host port connect-timeout tls)
+    :else (open-tcp host port connect-timeout)))
+
+(defn open
+  "Open a socket to a host and port or a Unix domain socket path. TCP sockets
+  are wrapped in TLS when there's a :tls map and tunneled through SSH when
+  there's an :ssh destination. Failed attempts are retried :retries times,
+  :retry-delay milliseconds apart. Returns the input and output streams along
+  with a function that closes everything."
+  [{:keys [host port socket connect-timeout retries retry-delay]
+    :or {connect-timeout 10000, retries 0, retry-delay 1000}
+    :as opts}]
+  (let [opts (assoc opts :connect-timeout connect-timeout)
+        target (or socket (str host ":" port))]
+    (loop [attempt 1]
+      (let [result (try
+                     (open* opts)
+                     (catch Exception e
+                       e))]
+        (cond
+          (not (instance? Exception result))
+          result
+
+          (<= attempt retries)
+          (do
+            (log/warn "Failed to connect to" target "attempt" attempt "-" result)
+            (Thread/sleep retry-delay)
+            (recur (inc attempt)))
+
+          :else
+          (throw (ex-info (str "Failed to connect to " target
+                               " after " (util/count-str attempt "attempt")
+                               " with a " connect-timeout "ms timeout: "
+                               (.getMessage ^Exception result))
+                          {:target target}
+                          result)))))))
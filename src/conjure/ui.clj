@@ -1,93 +1,332 @@
 (ns conjure.ui
   "Handle displaying and managing what's visible to the user."
-  (:require [conjure.nvim :as nvim]
+  (:require [clojure.string :as str]
+            [conjure.nvim :as nvim]
             [conjure.util :as util]
             [conjure.code :as code]))
 
-(def ^:private log-window-widths {:small 40 :large 80})
-(def ^:private max-log-buffer-length 2000)
+(def ^:private log-window-sizes
+  {"vertical" {:small 40, :large 80}
+   "horizontal" {:small 10, :large 20}})
+(def ^:private default-log-max-lines 2000)
+(def ^:private default-log-file-max-bytes (* 10 1024 1024))
+(def ^:private default-log-fold-lines 50)
 (defonce ^:private log-buffer-name "/tmp/conjure.cljc")
 (def ^:private welcome-msg "; conjure/out | Welcome to Conjure!")
+(def ^:private default-result-limit 10000)
+(defonce ^:private last-truncated! (atom nil))
+(def ^:private log-config-ttl 1000)
+(defonce ^:private log-config! (atom nil))
+
+;; Every entry written to the log with its origin, so the log can be narrowed
+;; down to (or away from) some connections and then restored again.
+(defonce ^:private log-entries! (atom []))
+(defonce ^:private log-filter! (atom nil))
+(defonce ^:private log-follow?! (atom true))
+
+(defn- log-config
+  "Every g:conjure_log_* option the log needs, read in one go. Cached for a
+  moment since busy output can append many times a second."
+  []
+  (let [now (System/currentTimeMillis)
+        {:keys [read-at config]} @log-config!]
+    (if (and config (< (- now read-at) log-config-ttl))
+      config
+      (let [config (nvim/configs {:log-layout "vertical"
+                                  :log-size-small nil
+                                  :log-size-large nil
+                                  :log-max-lines default-log-max-lines
+                                  :log-file nil
+                                  :log-file-max-bytes default-log-file-max-bytes
+                                  :log-fold-lines default-log-fold-lines
+                                  :log-verbosity "normal"})]
+        (reset! log-config! {:read-at now, :config config})
+        config))))
 
 (defn upsert-log
-  "Get, create, or update the log window and buffer."
+  "Get, create, or update the log window and buffer according to the
+  g:conjure_log_* options. Returns the buffer and window along with the log
+  settings append needs. Pass the :config in if you've already read it."
   ([] (upsert-log {}))
-  ([{:keys [focus? resize? width] :or {focus? false, resize? false, width :small}}]
-   (-> (nvim/call-lua-function
-         :upsert-log
-         log-buffer-name
-         (get log-window-widths width)
-         focus?
-         resize?)
-       (util/snake->kw-map))))
+  ([{:keys [focus? resize? width config] :or {focus? false, resize? false, width :small}}]
+   (let [{:keys [log-layout log-size-small log-size-large log-max-lines
+                 log-file log-file-max-bytes log-fold-lines]}
+         (or config (log-config))
+         size (or (get {:small log-size-small, :large log-size-large} width)
+                  (get-in log-window-sizes [log-layout width])
+                  (get-in log-window-sizes ["vertical" width]))]
+     (-> (nvim/call-lua-function
+           :upsert-log
+           log-buffer-name
+           size
+           focus?
+           resize?
+           log-layout)
+         (util/snake->kw-map)
+         (assoc :trim-at log-max-lines
+                :fold-lines log-fold-lines
+                :log-file log-file
+                :log-file-max-bytes log-file-max-bytes)))))
 
 (defn close-log
   "Closes the log window. In other news: Bear shits in woods."
   []
   (nvim/call-lua-function :close-log log-buffer-name))
 
+(defn- visible?
+  "If entries from the origin get through the log filter, Conjure's own
+  messages always do."
+  [origin]
+  (let [{:keys [only hide]} @log-filter!]
+    (or (= origin :conjure)
+        (and (or (nil? only) (contains? only origin))
+             (not (contains? hide origin))))))
+
 (defn append
-  "Append the message to the log, prefixed by the origin/kind. If it's code
-  then it won't prefix every line with the source, it'll place the whole string
-  below the origin/kind comment."
-  [{:keys [origin kind msg code?] :or {code? false}}]
-
-  (let [prefix (str "; " (name origin) "/" (name kind))]
-    (nvim/append-lines
-      (merge
-        (upsert-log)
-        {:header welcome-msg
-         :trim-at max-log-buffer-length
-         :lines (if code?
-                  (into [(str prefix " ⤸")] (util/split-lines msg))
-                  (for [line (util/split-lines msg)]
-                    (str prefix " | " line)))}))))
+  "Append the message to the log, prefixed by the origin/kind and an optional
+  note. If it's code then it won't prefix every line with the source, it'll
+  place the whole string below the origin/kind comment. Code longer than
+  g:conjure_log_fold_lines is folded away, za opens it. ANSI escapes such as
+  colours are stripped out first. A :conn is shown by its :label, if it has
+  one, and its :color highlights that part of the prefix. Messages marked as
+  :info? are left out entirely when g:conjure_log_verbosity is quiet."
+  [{:keys [origin conn kind msg code? note info?] :or {code? false}}]
+
+  (let [config (log-config)]
+    (when-not (and info? (= (:log-verbosity config) "quiet"))
+      (let [msg (util/strip-ansi msg)
+            origin (or origin (:tag conn))
+            label (str "; " (or (:label conn) (name origin)))
+            prefix (str label "/" (name kind) (when note (str " (" note ")")))
+            lines (if code?
+                    (into [(str prefix " ⤸")] (util/split-lines msg))
+                    (for [line (util/split-lines msg)]
+                      (str prefix " | " line)))
+            {:keys [trim-at fold-lines log-file log-file-max-bytes] :as log} (upsert-log {:config config})]
+        (swap! log-entries! (fn [entries]
+                              (let [entries (conj entries {:origin origin, :lines lines})]
+                                (cond-> entries
+                                  (> (count entries) trim-at) (subvec (quot trim-at 2))))))
+        (when-not (str/blank? log-file)
+          (util/append-to-file {:path log-file, :lines lines, :max-bytes log-file-max-bytes}))
+        (when (visible? origin)
+          (nvim/append-lines
+            (merge log {:header welcome-msg
+                        :follow? @log-follow?!
+                        :lines lines
+                        :fold? (and code? (pos? fold-lines) (> (count lines) fold-lines))
+                        :highlight (when-let [color (:color conn)]
+                                     {:color color
+                                      :width (count (.getBytes ^String label "UTF-8"))
+                                      :lines (if code? 1 (count lines))})})))))))
 
 (defn info
   "For general information from Conjure, this is like
-  a println from the system itself."
+  a println from the system itself. Left out of the log entirely when
+  g:conjure_log_verbosity is quiet."
   [& parts]
-  (append {:origin :conjure, :kind :out, :msg (util/join-words parts)}))
+  (append {:origin :conjure, :kind :out, :info? true, :msg (util/join-words parts)}))
 
 (defn error
   "For errors out of Conjure that shouldn't go to stderr."
   [& parts]
   (append {:origin :conjure, :kind :err, :msg (util/join-words parts)}))
 
+(defn filter-log!
+  "Show only the entries from the :only tags, or everything but the :hide
+  tags, redrawing the log from what's been written so far. Nil shows it all."
+  [log-filter]
+  (reset! log-filter! log-filter)
+  (nvim/replace-lines
+    (merge (upsert-log)
+           {:header welcome-msg
+            :lines (->> @log-entries!
+                        (filter (comp visible? :origin))
+                        (mapcat :lines))}))
+  (info (cond
+          (:only log-filter) (str "Log showing only " (str/join ", " (:only log-filter)))
+          (:hide log-filter) (str "Log hiding " (str/join ", " (:hide log-filter)))
+          :else "Log showing everything")))
+
+(defn toggle-log-follow
+  "Stop or start scrolling the log to the bottom as new entries arrive."
+  []
+  (if (swap! log-follow?! not)
+    (info "Log following new entries")
+    (info "Log no longer following new entries")))
+
+(defn clear-log
+  "Empty the log buffer and forget everything we remember writing to it."
+  []
+  (reset! log-entries! [])
+  (reset! last-truncated! nil)
+  (nvim/replace-lines (merge (upsert-log) {:header welcome-msg, :lines []})))
+
+(defn prompt
+  "Ask the user to type something in, nil if they cancelled."
+  [msg]
+  (nvim/input msg))
+
 (defn doc
   "Results from a (doc ...) call."
   [{:keys [conn resp]}]
-  (append {:origin (:tag conn), :kind :doc, :msg (:val resp)}))
+  (append {:conn conn, :kind :doc, :msg (:val resp)}))
 
-(defn test*
-  "Results from tests."
+(defn floating
+  "Display some text in a floating window at the cursor instead of the log."
+  [msg]
+  (nvim/call-lua-function :open-float (util/split-lines msg)))
+
+(defn source
+  "Results from a (source ...) call, displayed as code so it's highlighted."
   [{:keys [conn resp]}]
-  (append {:origin (:tag conn)
-           :kind :test
-           :msg (if (string? (:val resp))
-                  (:val resp)
-                  (pr-str (:val resp)))}))
+  (append {:conn conn, :kind :source, :code? true, :msg (:val resp)}))
+
+(defn examples
+  "ClojureDocs examples, displayed as code."
+  [{:keys [conn msg]}]
+  (append {:conn conn, :kind :examples, :code? true, :msg msg}))
+
+(defn- test-summary
+  "A single line verdict for the counts clojure.test gives us."
+  [{:keys [test pass fail error] :or {test 0, pass 0, fail 0, error 0}}]
+  (if (zero? (+ fail error))
+    (str "PASSED " test " tests with " pass " assertions")
+    (str "FAILED " fail " failures and " error " errors across " test " tests")))
+
+(defn classpath
+  "Classpath entries grouped into source directories and jars."
+  [{:keys [conn resp]}]
+  (let [{:keys [dirs jars]} (:val resp)]
+    (append {:conn conn
+             :kind :classpath
+             :msg (if (and (empty? dirs) (empty? jars))
+                    "No matching classpath entries"
+                    (->> [(when (seq dirs) (cons "Directories:" (map #(str "  " %) (sort dirs))))
+                          (when (seq jars) (cons "Jars:" (map #(str "  " %) (sort jars))))]
+                         (apply concat)
+                         (str/join "\n")))})))
+
+(defn refresh
+  "The report from a tools.namespace refresh, with any error at the end."
+  [{:keys [conn resp]}]
+  (let [{:keys [out error]} (:val resp)]
+    (append {:conn conn
+             :kind :refresh
+             :msg (str (str/trim-newline out)
+                       (when error
+                         (str "\n" (str/join ":" (remove nil? [(:file error) (:line error)]))
+                              (when (:file error) " ")
+                              (:message error))))})))
+
+(defn test*
+  "Results from tests, Clojure tests get a summary line at the end and anything
+  they printed goes above the report under its own test-out kind. When quiet?
+  passing Clojure tests only get that summary line in the command line."
+  [{:keys [conn resp quiet?]}]
+  (let [{:keys [val]} resp
+        summary (when (map? val) (:summary val))]
+    (if (and quiet? summary (zero? (+ (:fail summary 0) (:error summary 0))))
+      (nvim/notify (str "Conjure: " (name (:tag conn)) " " (test-summary summary)))
+      (do
+        (when-not (str/blank? (:stdout val))
+          (append {:conn conn
+                   :kind :test-out
+                   :msg (str/trim-newline (:stdout val))}))
+        (append {:conn conn
+                 :kind :test
+                 :msg (cond
+                        (string? val) val
+                        summary (str (str/trim-newline (:out val))
+                                     "\n" (test-summary summary))
+                        :else (pr-str val))})))))
 
 (defn eval*
   "When we send an eval and are awaiting a result, prints a short sample of the
-  code we sent."
-  [{:keys [conn code]}]
-  (append {:origin (:tag conn), :kind :eval, :msg (code/sample code)}))
+  code we sent prefixed by the namespace it's going to run in. With timestamp?
+  the time it was sent goes first."
+  [{:keys [conn code ns timestamp?]}]
+  (append {:conn conn
+           :kind :eval
+           :msg (str (when timestamp? (str "[" (util/timestamp-str) "] "))
+                     (when ns (str ns "=> "))
+                     (code/sample code))}))
+
+(defn- result-config
+  "How results should be displayed, from g:conjure_print_width,
+  g:conjure_print_length, g:conjure_print_level and g:conjure_result_limit."
+  []
+  (nvim/configs {:print-width nil
+                 :print-length nil
+                 :print-level nil
+                 :result-limit default-result-limit}))
 
 (defn result
-  "Format, if it's code, and display a result from an evaluation."
-  [{:keys [conn resp]}]
-  (let [code? (contains? #{:ret :tap} (:tag resp))]
-    (append {:origin (:tag conn)
-             :kind (:tag resp)
-             :code? code?
-             :msg (cond-> (:val resp)
-                    (= (:tag resp) :ret) (second)
-                    code? (util/pprint))})))
+  "Format, if it's code, and display a result from an evaluation. Really long
+  results are cut short, expand-last displays the whole thing. An :elapsed
+  time in milliseconds is shown next to the kind."
+  ([opts] (result opts nil))
+  ([{:keys [conn resp elapsed] :as opts} config]
+   (let [code? (contains? #{:ret :tap} (:tag resp))
+
+         ;; Output doesn't need any config, saves a few calls to Neovim.
+         {:keys [print-width print-length print-level result-limit]}
+         (or config (when code? (result-config)))
+         msg (cond-> (:val resp)
+               (= (:tag resp) :ret) (second)
+               code? (util/pprint {:width print-width
+                                   :max-length print-length
+                                   :max-depth print-level}))
+         [msg truncated] (if (and code? result-limit)
+                           (util/truncate msg result-limit)
+                           [msg 0])]
+     (when (pos? truncated)
+       (reset! last-truncated! opts))
+     (when (= (:tag resp) :err)
+       (when-let [warnings (seq (code/compiler-warnings msg))]
+         (nvim/add-to-quickfix (str "Compiler warnings on " (name (:tag conn))) warnings)))
+     (append {:conn conn
+              :kind (:tag resp)
+              :note (when elapsed (util/elapsed-str elapsed))
+              :code? code?
+              :msg (cond-> msg
+                     (pos? truncated)
+                     (str "\n;; ... " truncated " more characters, :ConjureExpandLast to see them"))}))))
+
+(defn hud
+  "The latest result or error in the corner of the editor for a moment."
+  [{:keys [conn resp timeout]}]
+  (let [[status value] (:val resp)
+        lines (util/split-lines (if (= status :ok)
+                                  (util/pprint value)
+                                  (str (or (:cause value) (pr-str value)))))]
+    (nvim/open-hud {:lines (cons (str "; " (name (:tag conn)) "/" (if (= status :ok) "ret" "err"))
+                                 (take 9 lines))
+                    :timeout timeout
+                    :hl (if (= status :ok) "NormalFloat" "ErrorMsg")})))
+
+(defn virtual-text
+  "A short sample of an eval result at the end of the line it came from."
+  [{:keys [buf line resp]}]
+  (let [[status value] (:val resp)]
+    (nvim/set-virtual-text
+      {:buf buf
+       :line line
+       :text (if (= status :ok)
+               (str "=> " (code/sample (util/pprint value)))
+               (str "!! " (code/sample (str (or (:cause value) (pr-str value))))))
+       :hl (if (= status :ok) "Comment" "ErrorMsg")})))
+
+(defn expand-last
+  "Display the last truncated result in full."
+  []
+  (if-let [opts @last-truncated!]
+    (result opts (assoc (result-config) :result-limit nil))
+    (error "Nothing has been truncated")))
 
 (defn load-file*
   "When we ask to load a whole file from disk."
   [{:keys [conn path]}]
-  (append {:origin (:tag conn)
+  (append {:conn conn
            :kind :load-file
            :msg path}))
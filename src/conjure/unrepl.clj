@@ -0,0 +1,133 @@
+(ns conjure.unrepl
+  "unrepl connections that look like prepl connections from the outside."
+  (:require [clojure.core.async :as a]
+            [clojure.java.io :as io]
+            [clojure.walk :as walk]
+            [taoensso.timbre :as log]
+            [conjure.ui :as ui]
+            [conjure.util :as util]
+            [conjure.socket :as socket])
+  (:import [java.io PushbackReader]))
+
+(defn- read-msg
+  "Read the next message from the unrepl output. Tagged literals such as
+  elisions are kept as tagged literals so they can be printed again later."
+  [rdr eof]
+  (binding [*default-data-reader-fn* tagged-literal
+            *read-eval* false]
+    (read {:eof eof, :read-cond :allow} rdr)))
+
+(defn ->prepl
+  "Translate an unrepl message tuple into the prepl style maps the rest of the
+  system expects. Values are printed back to strings so any elisions appear in
+  the log along with the :get form that fetches the rest."
+  [msg]
+  (when (vector? msg)
+    (let [[tag payload] msg]
+      (case tag
+        :eval {:tag :ret, :val (pr-str payload)}
+        :out {:tag :out, :val (str payload)}
+        :err {:tag :err, :val (str payload)}
+
+        ;; Every eval needs a :ret, even if it blew up.
+        :exception {:tag :ret, :val (pr-str [:error payload])}
+        nil))))
+
+(defn elision?
+  "Is this one of the markers unrepl leaves where it cut a value short?"
+  [x]
+  (and (tagged-literal? x)
+       (= (:tag x) 'unrepl/...)
+       (map? (:form x))
+       (contains? (:form x) :get)))
+
+(defn first-elision
+  "The first elision inside the value, nil if nothing was cut short."
+  [value]
+  (first (filter elision? (tree-seq coll? seq value))))
+
+(defn splice
+  "Put the fetched expansion in place of the elision within the value. The rest
+  of a sequence is spliced into it, the rest of a map is merged into it and
+  anything else, such as a value nested too deep to print, replaces the
+  marker."
+  [value elision expansion]
+  (walk/postwalk
+    (fn [x]
+      (cond
+        (and (map-entry? x) (identical? elision (val x)))
+        (clojure.lang.MapEntry/create (key x) expansion)
+
+        (and (sequential? x)
+             (not (map-entry? x))
+             (some #(identical? elision %) x))
+        (let [spliced (mapcat #(cond
+                                 (not (identical? elision %)) [%]
+                                 (sequential? expansion) expansion
+                                 :else [expansion])
+                              x)]
+          (if (vector? x) (vec spliced) spliced))
+
+        (and (map? x) (contains? x elision) (coll? expansion))
+        (into (dissoc x elision) expansion)
+
+        :else x))
+    value))
+
+(defn connect
+  "Connect to a plain socket REPL, upgrade it by sending the unrepl blob found
+  at the :blob path and then return channels that behave like the ones from
+  conjure.prepl/connect."
+  [{:keys [tag blob] :as opts}]
+  (let [[eval-chan read-chan] (repeatedly #(a/chan 32))
+        writer! (promise)]
+
+    (util/thread
+      "unrepl reader loop"
+      (try
+        (log/info "Connecting through unrepl" tag)
+        (let [{:keys [input output close]} (socket/open opts)
+              rdr (-> input (io/reader) (PushbackReader.))
+              writer (io/writer output)
+              eof (Object.)]
+          (log/trace "Sending unrepl blob to" tag "from" blob)
+          (util/write writer (str (slurp (io/file blob)) "\n"))
+          (deliver writer! {:close close, :writer writer})
+
+          (loop []
+            (let [msg (read-msg rdr eof)]
+              (when-not (identical? msg eof)
+                (log/trace "Read from unrepl" tag "-" msg)
+                (when-let [out (->prepl msg)]
+                  (a/>!! read-chan out))
+                (recur)))))
+
+        (catch Exception e
+          (log/error "Error from unrepl:" e)
+          (when-not (realized? writer!)
+            (ui/error "Error from" tag e)))
+
+        (finally
+          (log/trace "Exited unrepl reader, cleaning up" tag)
+          (deliver writer! nil)
+          (a/close! read-chan))))
+
+    (util/thread
+      "unrepl writer loop"
+      (when-let [{:keys [close writer]} @writer!]
+        (try
+          (loop []
+            (when-let [code (a/<!! eval-chan)]
+              (log/trace "Writing to tag:" tag "-" code)
+              (util/write writer (str code "\n"))
+              (recur)))
+
+          (catch Exception e
+            (log/error "Error from eval-chan writing:" e))
+
+          (finally
+            (log/trace "Exited eval-chan loop, closing unrepl socket" tag)
+            (close)))))
+
+    {:eval-chan eval-chan
+     :read-chan read-chan}))
@@ -2,11 +2,13 @@
   "Anything useful and generic that's shared by multiple namespaces."
   (:require [clojure.main :as clj]
             [clojure.string :as str]
+            [clojure.java.io :as io]
             [clojure.core.memoize :as memo]
             [taoensso.timbre :as log]
             [zprint.core :as zp]
             [camel-snake-kebab.core :as csk]
-            [camel-snake-kebab.extras :as cske]))
+            [camel-snake-kebab.extras :as cske])
+  (:import [java.util.zip ZipFile]))
 
 (defn join-words [parts]
   (str/join " " parts))
@@ -26,6 +28,14 @@
        r
        (subs s (min (count s) end))))
 
+(defn render-template
+  "Replace each {{placeholder}} in the template with (lookup :placeholder), the
+  lookup is only called for placeholders that are actually used."
+  [template lookup]
+  (str/replace template #"\{\{\s*([\w-]+)\s*\}\}"
+               (fn [[_ k]]
+                 (str (lookup (keyword k))))))
+
 (def ^:dynamic get-env-fn #(System/getenv %))
 
 (defn env
@@ -38,18 +48,35 @@
 (defn error->str [error]
   (-> error Throwable->map clj/ex-triage clj/ex-str))
 
+(defn strip-ansi
+  "Remove ANSI escape sequences such as colours from some output, test
+  runners and loggers love them but they're just noise in a buffer."
+  [s]
+  (str/replace s #"\u001b(\[[0-9;?]*[ -/]*[@-~]|\][^\u0007\u001b]*(\u0007|\u001b\\)|[@-_])" ""))
+
 (defn escape-quotes [s]
   (str/escape s {\\ "\\\\"
                  \" "\\\""}))
 
 (defn pprint
-  "Format the given data, assuming it's already parsed."
-  [data]
-  (try
-    (zp/zprint-str data)
-    (catch Exception e
-      (log/error "Error while pretty printing" e)
-      (pr-str data))))
+  "Format the given data, assuming it's already parsed. Takes an optional map
+  of :width, :max-length (like *print-length*) and :max-depth (like
+  *print-level*), nil values are ignored."
+  ([data] (pprint data {}))
+  ([data opts]
+   (try
+     (zp/zprint-str data (into {} (remove (comp nil? val)) opts))
+     (catch Exception e
+       (log/error "Error while pretty printing" e)
+       (pr-str data)))))
+
+(defn truncate
+  "Cut a string down to at most limit characters. Returns the string and how
+  many characters were dropped."
+  [s limit]
+  (if (> (count s) limit)
+    [(subs s 0 limit) (- (count s) limit)]
+    [s 0]))
 
 (defn regexp? [o]
   (instance? java.util.regex.Pattern o))
@@ -85,13 +112,149 @@
   (memo/lru #(cske/transform-keys kw->snake %)))
 
 (defn count-str
-  "Pluralises a string depending on the amount."
+  "Pluralises a string depending on the amount, either a number or a collection
+  to count."
   [items description]
-  (let [amount (count items)
+  (let [amount (if (number? items) items (count items))
         plural? (not= amount 1)]
     (str amount " " description (when plural? "s"))))
 
+(defn duration-str
+  "Milliseconds as a short human readable duration such as 1h 5m or 42s."
+  [ms]
+  (let [secs (quot ms 1000)
+        parts [[(quot secs 86400) "d"]
+               [(rem (quot secs 3600) 24) "h"]
+               [(rem (quot secs 60) 60) "m"]
+               [(rem secs 60) "s"]]]
+    (or (->> parts
+             (drop-while (comp zero? first))
+             (take 2)
+             (remove (comp zero? first))
+             (map (fn [[n unit]] (str n unit)))
+             (seq)
+             (join-words))
+        "0s")))
+
+(defn elapsed-str
+  "Milliseconds as a precise duration, fine detail matters for short ones."
+  [ms]
+  (cond
+    (< ms 1000) (str ms "ms")
+    (< ms 60000) (format "%.2fs" (/ ms 1000.0))
+    :else (duration-str ms)))
+
+(defn timestamp-str
+  "The current wall clock time such as 14:02:53."
+  []
+  (.format (java.time.LocalTime/now)
+           (java.time.format.DateTimeFormatter/ofPattern "HH:mm:ss")))
+
+(defn levenshtein
+  "The number of single character edits it takes to turn a into b."
+  [a b]
+  (peek
+    (reduce
+      (fn [prev ca]
+        (reduce
+          (fn [row [n cb]]
+            (conj row (min (inc (peek row))
+                           (inc (nth prev (inc n)))
+                           (+ (nth prev n) (if (= ca cb) 0 1)))))
+          [(inc (first prev))]
+          (map-indexed vector b)))
+      (vec (range (inc (count b))))
+      a)))
+
+(defn fuzzy-rank
+  "Order the items by how well (word-fn item) matches the query and keep the
+  best limit of them. Exact prefix matches come first, then words that show up
+  more often in the frequencies map, then the closest by edit distance."
+  [{:keys [query items word-fn frequencies limit]
+    :or {word-fn identity, frequencies {}}}]
+  (cond->> (sort-by
+             (fn [item]
+               (let [word (word-fn item)]
+                 [(if (str/starts-with? word query) 0 1)
+                  (- (get frequencies word 0))
+                  (levenshtein query word)
+                  word]))
+             items)
+    limit (take limit)))
+
 (defn free-port []
   (let [socket (java.net.ServerSocket. 0)]
     (.close socket)
     (.getLocalPort socket)))
+
+(defn wait-until
+  "Call pred every 100ms until it returns something truthy or the timeout (in
+  milliseconds) runs out. Returns the last result of pred."
+  [timeout pred]
+  (let [deadline (+ (System/currentTimeMillis) timeout)]
+    (loop []
+      (or (pred)
+          (when (< (System/currentTimeMillis) deadline)
+            (Thread/sleep 100)
+            (recur))))))
+
+(defn port-open?
+  "Is something accepting connections on the host and port right now?"
+  [host port]
+  (try
+    (.close (java.net.Socket. ^String host (int port)))
+    true
+    (catch java.io.IOException _
+      false)))
+
+(defn wait-for-port
+  "Block until something is accepting connections on the host and port or
+  the timeout (in milliseconds) runs out. Returns true if the port opened."
+  [{:keys [host port timeout]}]
+  (boolean (wait-until timeout #(port-open? host port))))
+
+(defn extract-zipfile
+  "Copy the file out of a zipfile:/path/to.jar::path/in/jar style path, the kind
+  Vim's zip plugin understands, into a temporary directory so it can be opened
+  like any other file. Returns nil if it's not one of those paths or the jar
+  isn't on this machine."
+  [path]
+  (when-let [[_ jar entry] (re-matches #"zipfile:(.+)::(.+)" path)]
+    (let [jar-file (io/file jar)
+          target (io/file (System/getProperty "java.io.tmpdir")
+                          "conjure-sources" (.getName jar-file) entry)]
+      (when (and (.exists jar-file) (not (.exists target)))
+        (with-open [zip (ZipFile. jar-file)]
+          (when-let [zip-entry (.getEntry zip ^String entry)]
+            (io/make-parents target)
+            (with-open [input (.getInputStream zip zip-entry)]
+              (io/copy input target)))))
+      (when (.exists target)
+        (str target)))))
+
+(defn find-up
+  "Walk up from the path looking for a file with the name, returns the closest
+  one as a java.io.File or nil."
+  [path file-name]
+  (loop [dir (io/file path)]
+    (when dir
+      (let [f (io/file dir file-name)]
+        (if (.isFile f)
+          f
+          (recur (.getParentFile dir)))))))
+
+(defn append-to-file
+  "Append the lines to the file, once it's bigger than max-bytes it's moved to
+  path.1 (replacing the last one) and we start again. Failures are logged
+  rather than thrown, losing a line of log shouldn't break anything."
+  [{:keys [path lines max-bytes]}]
+  (try
+    (let [file (io/file path)]
+      (when (and max-bytes (> (.length file) max-bytes))
+        (let [old (io/file (str path ".1"))]
+          (.delete old)
+          (.renameTo file old)))
+      (io/make-parents file)
+      (spit file (str (join-lines lines) "\n") :append true))
+    (catch Exception e
+      (log/warn "Couldn't write the log to" path e))))
@@ -0,0 +1,25 @@
+(ns conjure.clojuredocs-test
+  (:require [clojure.test :as t]
+            [cheshire.core :as json]
+            [conjure.clojuredocs :as clojuredocs])
+  (:import [java.io File]))
+
+(t/deftest lookup-and-render
+  (let [export (File/createTempFile "clojuredocs" ".json")]
+    (try
+      (spit export (json/generate-string
+                     {:vars [{:ns "clojure.core"
+                              :name "inc"
+                              :examples [{:body "(inc 1)\n;;=> 2\n"}]
+                              :see-alsos [{:to-var {:ns "clojure.core", :name "dec"}}]}]}))
+      (let [found (clojuredocs/lookup (str export) "clojure.core/inc")]
+        (t/is (= found {:examples ["(inc 1)\n;;=> 2\n"]
+                        :see-alsos ["clojure.core/dec"]}))
+        (t/is (= (clojuredocs/render "clojure.core/inc" found)
+                 (str ";; 1 example for clojure.core/inc\n"
+                      ";; Example 1\n"
+                      "(inc 1)\n;;=> 2\n"
+                      ";; See also: clojure.core/dec"))))
+      (t/is (nil? (clojuredocs/lookup (str export) "clojure.core/nope")))
+      (finally
+        (.delete export)))))
@@ -1,12 +1,63 @@
 (ns conjure.code-test
   (:require [clojure.test :as t]
+            [clojure.string :as str]
+            [clojure.repl]
             [conjure.code :as code]))
 
+(defn- run
+  "Evaluate generated code in this JVM so we know it actually works rather than
+  just looking right. Anything printed to stderr is thrown away."
+  [code]
+  (binding [*err* (java.io.StringWriter.)]
+    (load-string code)))
+
 (t/deftest sample
   (t/is (= (code/sample "this is some code") "this is some code"))
   (t/is (= (code/sample "this is some long code and it exceeds the character limit")
            "this is some long code and it exceeds the …")))
 
+(t/deftest result-comment
+  (t/is (= (code/result-comment "20") [";; => 20"]))
+  (t/is (= (code/result-comment "{:a 1\n :b 2}") [";; => {:a 1" ";;     :b 2}"])))
+
+(t/deftest compiler-warnings
+  (t/is (= [] (code/compiler-warnings "just some output")))
+  (t/is (= [{:filename "/src/foo.clj", :lnum 12, :col 3
+             :text "Reflection: call to method trim can't be resolved (target class is unknown)."}
+            {:filename "/src/foo.clj", :lnum 14, :col 5
+             :text "Boxed math: call: public static java.lang.Number clojure.lang.Numbers.unchecked_inc(java.lang.Object)."}]
+           (code/compiler-warnings
+             (str "Reflection warning, /src/foo.clj:12:3 - call to method trim can't be resolved (target class is unknown).\n"
+                  "Boxed math warning, /src/foo.clj:14:5 - call: public static java.lang.Number clojure.lang.Numbers.unchecked_inc(java.lang.Object).\n")))))
+
+(t/deftest unbalanced
+  (t/is (nil? (code/unbalanced "(+ 10 [20] {:a 30})")))
+  (t/is (nil? (code/unbalanced "(str \")\" \\( \"\\\"(\") ; (((\n")))
+  (t/is (= (code/unbalanced "(+ 10\n(inc 20)") "Unclosed ( from line 1"))
+  (t/is (= (code/unbalanced "(+ 10 20))") "Unexpected ) on line 1"))
+  (t/is (= (code/unbalanced "(let [x 1)\n  x)") "Unexpected ) on line 1, expected ]"))
+  (t/is (= (code/unbalanced "(str \"hello)") "Unterminated string")))
+
+(t/deftest split-forms
+  (t/is (= (code/split-forms "") []))
+  (t/is (= (code/split-forms "(+ 10 10)")
+           [{:code "(+ 10 10)", :line 1, :column 1}]))
+  (t/is (= (code/split-forms "(def a 1) ; one\n  a, @b\n#_nope :kw")
+           [{:code "(def a 1)", :line 1, :column 1}
+            {:code "a", :line 2, :column 3}
+            {:code "@b", :line 2, :column 6}
+            {:code "#_nope :kw", :line 3, :column 1}]))
+  (t/is (= (code/split-forms "::nope/alias (+ 1 2)")
+           [{:code "::nope/alias (+ 1 2)", :line 1, :column 1}])))
+
+(t/deftest document-symbols
+  (t/is (= (code/document-symbols "(ns foo.bar)\n\n(defn- ^:private baz [x]\n  (def nested 1))\n(s/def ::thing string?)\n(defmethod handle :foo [_])")
+           [{:name "foo.bar", :kind "ns", :line 1}
+            {:name "baz", :kind "defn-", :line 3}
+            {:name "::thing", :kind "s/def", :line 5}
+            {:name "handle", :kind "defmethod", :line 6}]))
+  (t/is (empty? (code/document-symbols "(+ 1 2)"))))
+
 (t/deftest parse-code
   (t/is (= (code/parse-code "{:foo :bar}") {:foo :bar})))
 
@@ -16,3 +67,252 @@
   (t/is (= (code/parse-ns "(ns some.ns-woo)") 'some.ns-woo))
   (t/is (= (code/parse-ns "(ns some.ns-woo \"some docs\")") 'some.ns-woo))
   (t/is (= (code/parse-ns "(ns ^{:doc \"foo\"} best.ns)") 'best.ns)))
+
+(t/deftest parse-aliases
+  (t/is (= (code/parse-aliases "(ns foo (:require [clojure.string :as str] clojure.set [clojure [walk :as w] [edn]] [foo.spec :as-alias fs]) (:import [java.io File]))")
+           '{str clojure.string, w clojure.walk, fs foo.spec}))
+  (t/is (= (code/parse-aliases "(ns foo)") {}))
+  (t/is (nil? (code/parse-aliases "(+ 1 2)"))))
+
+(t/deftest resolve-alias
+  (let [ctx {:ns 'foo.bar, :aliases '{str clojure.string}}]
+    (t/is (= (code/resolve-alias ctx "str/join") "clojure.string/join"))
+    (t/is (= (code/resolve-alias ctx "::str/thing") ":clojure.string/thing"))
+    (t/is (= (code/resolve-alias ctx "::thing") ":foo.bar/thing"))
+    (t/is (= (code/resolve-alias ctx ":str/thing") ":str/thing"))
+    (t/is (= (code/resolve-alias ctx "set/union") "set/union"))
+    (t/is (= (code/resolve-alias ctx "map") "map"))))
+
+(t/deftest prelude-str
+  (t/is (re-find #"compliment" (code/prelude-str {:lang :clj})))
+  (t/is (not (re-find #"compliment" (code/prelude-str {:lang :clj, :bb? true})))))
+
+(t/deftest eval-str
+  (let [ctx {:ns 'conjure.code-test.sandbox}
+        jvm {:conn {:lang :clj}, :code "(+ 10 10)"}
+        bb (assoc-in jvm [:conn :bb?] true)]
+    (t/is (= (run (code/eval-str ctx jvm)) [:ok 20]))
+    (t/is (= (run (code/eval-str ctx (assoc jvm :code "(str *ns*)")))
+             [:ok "conjure.code-test.sandbox"]))
+    (t/testing "line and column of the quoted list, one after the quote"
+      (t/is (= (-> (run (code/eval-str ctx (assoc jvm :code "'(x)", :line 10, :column 5)))
+                   (second)
+                   (meta)
+                   (select-keys [:line :column]))
+               {:line 10, :column 6})))
+    (t/is (= (run (code/eval-str ctx (-> jvm
+                                         (assoc :code "*print-length*")
+                                         (assoc-in [:conn :bindings] '{*print-length* 10}))))
+             [:ok 10]))
+    (t/is (= (run (code/eval-str ctx (assoc jvm :code "#foo/bar 1")))
+             [:ok (tagged-literal 'foo/bar 1)]))
+    (let [[status emap] (run (code/eval-str ctx (assoc jvm :code "(throw (ex-info \"boom\" {}))")))]
+      (t/is (= status :error))
+      (t/is (= (:cause emap) "boom")))
+
+    (t/is (= (run (code/eval-str ctx bb)) [:ok 20]))
+    (t/is (= (run (code/eval-str ctx (-> bb
+                                         (assoc :code "*print-length*")
+                                         (assoc-in [:conn :bindings] '{*print-length* 10}))))
+             [:ok 10]))
+    (t/is (not (re-find #"clojure\.lang" (code/eval-str ctx bb))))))
+
+(defn- flush-recorder
+  "A writer that remembers when some output first made it through a flush."
+  [flushed-at!]
+  (proxy [java.io.StringWriter] []
+    (flush []
+      (when (pos? (.length (.getBuffer ^java.io.StringWriter this)))
+        (compare-and-set! flushed-at! nil (System/currentTimeMillis))))))
+
+(t/deftest eval-str-streaming
+  (let [ctx {:ns 'conjure.code-test.sandbox}
+        opts {:conn {:lang :clj}
+              :code "(print \"hi\") (Thread/sleep 500) (System/currentTimeMillis)"}
+        timings (fn [opts]
+                  (let [flushed-at! (atom nil)
+                        [_ returned-at] (binding [*out* (flush-recorder flushed-at!)]
+                                          (run (code/eval-str ctx opts)))]
+                    [@flushed-at! returned-at]))]
+    (t/testing "streamed output is flushed while the eval is still running"
+      (let [[flushed-at returned-at] (timings (assoc opts :stream? true))]
+        (t/is (< flushed-at returned-at))))
+    (t/testing "otherwise it's only flushed at the end"
+      (let [[flushed-at returned-at] (timings opts)]
+        (t/is (<= returned-at flushed-at))))))
+
+(t/deftest heartbeat-str
+  (t/is (= (code/heartbeat-str {:lang :cljs}) ":conjure/heartbeat\n"))
+  (t/is (= (code/heartbeat-str {:lang :clj, :bb? true}) ":conjure/heartbeat\n"))
+
+  (t/testing "the REPL history survives heartbeats"
+    (binding [*1 1, *2 2, *3 3]
+      (let [repl-eval (fn [code]
+                        ;; What the REPL does with every result.
+                        (let [ret (run code)]
+                          (set! *3 *2)
+                          (set! *2 *1)
+                          (set! *1 ret)
+                          ret))
+            heartbeat (code/heartbeat-str {:lang :clj})]
+        (t/is (= (repl-eval heartbeat) :conjure/heartbeat))
+        (t/is (= (repl-eval heartbeat) :conjure/heartbeat))
+        (t/is (= (repl-eval (code/eval-str {:ns 'conjure.code-test.sandbox}
+                                           {:conn {:lang :clj}, :code "[*1 *2 *3]"}))
+                 [:ok [1 2 3]]))))))
+
+(t/deftest shadow-eval-str
+  (let [wrapped (code/shadow-eval-str :app "(js/console.log \"hi\")")]
+    (t/is (re-find #"cljs-eval\s+:app" wrapped))
+    (t/is (re-find #"\\\"hi\\\"" wrapped))))
+
+(t/deftest doc-str
+  (let [clj (code/doc-str {:conn {:lang :clj}, :name "String/valueOf"})]
+    (t/is (re-find #"sym 'String/valueOf" clj))
+    (t/is (re-find #"clojure.java.javadoc/javadoc-url" clj))
+    (t/is (re-find #"\(eval \(list 'clojure.repl/doc sym\)\)" clj))
+    (t/is (re-find #"clojure.spec.alpha/get-spec" clj))
+    (t/is (re-find #"similar names" clj)))
+  (t/is (= (code/doc-str {:conn {:lang :cljs}, :name "map"})
+           "(with-out-str (cljs.repl/doc map))")))
+
+(t/deftest source-str
+  (t/is (= (code/source-str {:conn {:lang :clj}, :name "map"})
+           "(with-out-str (clojure.repl/source map))"))
+  (t/is (= (code/source-str {:conn {:lang :cljs}, :name "map"})
+           "(with-out-str (cljs.repl/source map))")))
+
+(t/deftest completions-str
+  (let [clj (code/completions-str {:ns 'foo} {:conn {:lang :clj}, :prefix "ma"})
+        bb (code/completions-str {:ns 'foo} {:conn {:lang :clj, :bb? true}, :prefix "ma"})]
+    (t/is (re-find #"compliment.core/completions" clj))
+    (t/is (re-find #"ns-map" clj))
+    (t/is (not (re-find #"compliment" bb)))
+    (t/is (re-find #"find-ns 'foo" bb))
+    (let [ranked (map :candidate (run (code/completions-str {:ns 'conjure.code-test}
+                                                            {:conn {:lang :clj, :bb? true}
+                                                             :prefix "ma"})))]
+      (t/is (= (take 2 ranked) ["map" "max"]))
+      (t/is (some #{"remove-all-methods"} ranked)))
+    (t/is (= (code/completions-str {} {:conn {:lang :cljs}, :prefix "ma"}) "[]"))))
+
+(t/deftest usages-str
+  (let [usages (code/usages-str {:name "foo/bar"})]
+    (t/is (re-find #"resolve 'foo/bar" usages))
+    (t/is (re-find #"\(all-ns\)" usages))))
+
+(t/deftest arglists-str
+  (t/is (= (code/arglists-str {:name "map"})
+           "(some->> (resolve 'map) meta :arglists pr-str)")))
+
+(t/deftest apropos-str
+  (t/is (= (code/apropos-str {:conn {:lang :clj}, :pattern "map-?ind"})
+           "(map str (clojure.repl/apropos #\"map-?ind\"))"))
+  (t/is (= (code/apropos-str {:conn {:lang :clj}, :pattern "\\d+"})
+           "(map str (clojure.repl/apropos #\"\\d+\"))"))
+  (t/is (= (code/apropos-str {:conn {:lang :cljs}, :pattern "\""})
+           "(map str (cljs.repl/apropos #\"\\\"\"))")))
+
+(t/deftest ns-publics-str
+  (let [clj (code/ns-publics-str {:conn {:lang :clj}, :ns 'foo.bar})
+        cljs (code/ns-publics-str {:conn {:lang :cljs}, :ns 'foo.bar})]
+    (t/is (re-find #"^\(when \(find-ns 'foo.bar\)" clj))
+    (t/is (re-find #"ns-publics 'foo.bar" cljs))
+    (t/is (not (re-find #"find-ns" cljs)))))
+
+(t/deftest spec-str
+  (t/is (re-find #"get-spec\) ::user\)" (code/spec-str {:conn {:lang :clj}, :name "::user"})))
+  (t/is (= (code/spec-str {:conn {:lang :cljs}, :name "create!"})
+           "(some-> (cljs.spec.alpha/get-spec `create!) (cljs.spec.alpha/form))")))
+
+(t/deftest macroexpand-str
+  (let [clj {:lang :clj}
+        cljs {:lang :cljs}]
+    (t/is (= (code/macroexpand-str {:conn clj, :code "(when x y)", :depth :one})
+             "(macroexpand-1 '(when x y))"))
+    (t/is (= (code/macroexpand-str {:conn clj, :code "(when x y)", :depth :full})
+             "(macroexpand '(when x y))"))
+    (t/is (re-find #"clojure.walk/macroexpand-all '\(when x y\)"
+                   (code/macroexpand-str {:conn clj, :code "(when x y)", :depth :all})))
+    (t/is (= (code/macroexpand-str {:conn cljs, :code "(when x y)", :depth :all})
+             "(macroexpand '(when x y))"))))
+
+(t/deftest load-file-str
+  (t/is (= (code/load-file-str "/foo/bar.clj") "(load-file \"/foo/bar.clj\")"))
+  (t/is (= (code/load-file-str "C:\\foo\\bar.clj") "(load-file \"C:\\\\foo\\\\bar.clj\")")))
+
+(t/deftest inspect-str
+  (t/is (= (run (code/inspect-str {:conn {:lang :clj}, :name "(atom 5)"})) 5))
+  (t/is (= (run (code/inspect-str {:conn {:lang :clj}, :name "[1 2]"})) [1 2]))
+  (t/is (not (realized? (run (code/inspect-str {:conn {:lang :clj}, :name "(promise)"})))))
+  (t/is (re-find #"satisfies\? IDeref" (code/inspect-str {:conn {:lang :cljs}, :name "my-atom"}))))
+
+(t/deftest require-str
+  (t/is (= (code/require-str {:ns 'foo.bar}) "(require 'foo.bar)"))
+  (t/is (= (code/require-str {:ns 'foo.bar, :reload? true}) "(require 'foo.bar :reload)")))
+
+(t/deftest add-lib-str
+  (let [code (code/add-lib-str {:lib 'hiccup/hiccup, :coord {:mvn/version "1.0.5"}})]
+    (t/is (re-find #"\(add-lib 'hiccup/hiccup \{:mvn/version \"1.0.5\"\}\)" code))
+    (t/is (re-find #"tools.deps.alpha.repl/add-lib\) 'hiccup/hiccup \{" code)))
+  (t/is (re-find #"\(add-lib 'hiccup/hiccup\)" (code/add-lib-str {:lib 'hiccup/hiccup}))))
+
+(t/deftest stack-frames-str
+  (let [[frame & more] (run (code/stack-frames-str {:trace [['clojure.core$map 'invokeStatic "core.clj" 2742]
+                                                            ['nope.nope$nope 'invoke "nope.clj" 1]]}))]
+    (t/is (empty? more))
+    (t/is (str/ends-with? (:filename frame) "clojure/core.clj"))
+    (t/is (= (:lnum frame) 2742))
+    (t/is (= (:text frame) "clojure.core/map invokeStatic"))))
+
+(t/deftest unmap-str
+  (t/is (= "(do (ns-unmap 'foo 'a) (ns-unmap 'foo 'b) '[a b])"
+           (code/unmap-str {:ns 'foo, :names ["a" "b"]}))))
+
+(t/deftest unused-requires-str
+  (let [code (code/unused-requires-str {:ns 'foo.bar, :path "/src/foo/bar.clj"})]
+    (t/is (re-find #"\(the-ns 'foo.bar\)" code))
+    (t/is (re-find #"clojure.java.io/reader \"/src/foo/bar.clj\"" code))))
+
+(t/deftest classpath-str
+  (let [{:keys [jars]} (run (code/classpath-str {:query "clojure"}))]
+    (t/is (seq jars))
+    (t/is (every? #(str/includes? % "clojure") jars)))
+  (t/is (= (run (code/classpath-str {:query "\"no such entry\""})) {})))
+
+(t/deftest refresh-str
+  (t/is (re-find #"clojure.tools.namespace.repl/refresh\)" (code/refresh-str {})))
+  (t/is (re-find #"clojure.tools.namespace.repl/refresh-all\)" (code/refresh-str {:all? true}))))
+
+(t/deftest run-tests-str
+  (let [clj (code/run-tests-str {:conn {:lang :clj}, :targets ['foo 'foo-test]})]
+    (t/is (re-find #"\(distinct \['foo 'foo-test\]\)" clj))
+    (t/is (re-find #"\(require ns :reload\)" clj))
+    (t/is (re-find #":summary \(select-keys summary" clj))
+    (t/is (re-find #"\*out\* stdout" clj)))
+  (t/is (re-find #"\(cljs.test/run-tests 'foo\)"
+                 (code/run-tests-str {:conn {:lang :cljs}, :targets ['foo]}))))
+
+(t/deftest run-tests-str-kaocha
+  (let [kaocha {:lang :clj, :test-runner :kaocha}]
+    (t/is (re-find #"\(\(resolve 'kaocha.repl/run\) 'foo-test\)"
+                   (code/run-tests-str {:conn kaocha, :targets ['foo-test]})))
+    (t/is (re-find #"\(\(resolve 'kaocha.repl/run\) 'foo/a\)"
+                   (code/run-test-str {:conn kaocha, :names ["foo/a"]})))
+    (t/is (re-find #"\(\(resolve 'kaocha.repl/run-all\) \)"
+                   (code/run-all-tests-str {:conn kaocha})))
+    (t/is (re-find #":kaocha.result/fail totals"
+                   (code/run-project-tests-str {:conn kaocha, :pattern "-test$"})))))
+
+(t/deftest run-test-str
+  (t/is (re-find #"test-vars \[\(var adds-up\)\]"
+                 (code/run-test-str {:conn {:lang :clj}, :names ["adds-up"]})))
+  (t/is (re-find #"test-vars \[\(var foo/a\) \(var foo/b\)\]"
+                 (code/run-test-str {:conn {:lang :clj}, :names ["foo/a" "foo/b"]})))
+  (t/is (re-find #"cljs.test/test-vars \[\(var adds-up\)\]"
+                 (code/run-test-str {:conn {:lang :cljs}, :names ["adds-up"]}))))
+
+(t/deftest run-project-tests-str
+  (let [code (code/run-project-tests-str {:pattern "-test$"})]
+    (t/is (re-find #"pattern #\"-test\$\"" code))
+    (t/is (re-find #"clojure.test/test-ns ns" code))))
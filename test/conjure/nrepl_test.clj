@@ -0,0 +1,44 @@
+(ns conjure.nrepl-test
+  (:require [clojure.test :as t]
+            [conjure.nrepl :as nrepl]
+            [conjure.code :as code])
+  (:import [java.io ByteArrayInputStream PushbackInputStream]))
+
+(defn- decode-str [s]
+  (nrepl/decode (PushbackInputStream. (ByteArrayInputStream. (.getBytes s "UTF-8")))))
+
+(t/deftest encode
+  (t/is (= (String. (nrepl/encode 10)) "i10e"))
+  (t/is (= (String. (nrepl/encode "foo")) "3:foo"))
+  (t/is (= (String. (nrepl/encode :foo)) "3:foo"))
+  (t/is (= (String. (nrepl/encode ["foo" 1])) "l3:fooi1ee"))
+  (t/is (= (String. (nrepl/encode {:op "eval", :code "(+ 1 2)"}))
+           "d4:code7:(+ 1 2)2:op4:evale"))
+  (t/is (= (count (nrepl/encode "λ")) 4)))
+
+(t/deftest decode
+  (t/is (= (decode-str "") nil))
+  (t/is (= (decode-str "i-5e") -5))
+  (t/is (= (decode-str "3:foo") "foo"))
+  (t/is (= (decode-str "2:λ") "λ"))
+  (t/is (= (decode-str "l3:fooi1ee") ["foo" 1]))
+  (t/is (= (decode-str "d2:op4:eval6:statusl4:doneee")
+           {"op" "eval", "status" ["done"]}))
+  (t/is (= (decode-str (String. (nrepl/encode {:a [1 {:b "c"}]})))
+           {"a" [1 {"b" "c"}]})))
+
+(t/deftest ->prepl
+  (t/is (= (nrepl/->prepl {"value" "3", "ns" "user"})
+           {:tag :ret, :val "3", :ns "user"}))
+  (t/is (= (nrepl/->prepl {"out" "hi\n"}) {:tag :out, :val "hi\n"}))
+  (t/is (= (nrepl/->prepl {"err" "oh no\n"}) {:tag :err, :val "oh no\n"}))
+  (t/is (= (nrepl/->prepl {"out" (str code/tap-marker "{:a 1}\n")}) {:tag :tap, :val "{:a 1}"}))
+  (t/is (= (nrepl/->prepl {"status" ["done"]}) nil))
+  (t/is (= (nrepl/->prepl {"ex" "class clojure.lang.ExceptionInfo"
+                           "root-ex" "class clojure.lang.ExceptionInfo"
+                           "status" ["eval-error"]})
+           {:tag :ret
+            :val "[:error {:ex \"class clojure.lang.ExceptionInfo\", :root-ex \"class clojure.lang.ExceptionInfo\"}]"}))
+  (t/is (= (nrepl/->prepl {"status" ["interrupted"]})
+           {:tag :ret, :val "[:error {:cause \"Interrupted\"}]"}))
+  (t/is (= (nrepl/->prepl {"status" ["need-input"]}) {:tag :need-input})))
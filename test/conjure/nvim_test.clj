@@ -34,7 +34,19 @@
            {:path "foo.clj"
             :buf 5
             :win 10
-            :ns 'foo})))
+            :ns 'foo
+            :aliases {}})))
+
+(t/deftest config
+  (defmethod call :nvim-eval [{[expr] :params}]
+    (when (= expr "get(g:, 'conjure_result_register', v:null)")
+      "+"))
+
+  (t/is (= (nvim/config :result-register) "+"))
+  (t/is (nil? (nvim/config :something-else)))
+  (t/is (= (nvim/config :something-else 10) 10))
+  (t/is (= (nvim/configs {:result-register nil, :something-else 10})
+           {:result-register "+", :something-else 10})))
 
 (t/deftest read-form
   (let [src ["(+ 10 10)"
@@ -105,3 +117,26 @@
     ["foo" "bar"])
 
   (t/is (= (nvim/read-buffer) "foo\nbar")))
+
+(t/deftest replace-form
+  (let [src ["(foo (+ 10 10) bar)"
+             "(let [x 1]"
+             "  x) :after"]
+        set-lines (atom nil)]
+    (defmethod call :nvim-get-current-buf [_] 5)
+    (defmethod call :nvim-buf-get-lines [{[_ start end] :params}]
+      (->> src (drop start) (take (- end start)) (vec)))
+    (defmethod call :nvim-buf-set-lines [{[_ start end _ lines] :params}]
+      (reset! set-lines [start end lines]))
+
+    (t/testing "single line"
+      (nvim/replace-form {:form "(+ 10 10)", :origin [1 6]} "20")
+      (t/is (= @set-lines [0 1 ["(foo 20 bar)"]])))
+
+    (t/testing "multiple lines"
+      (nvim/replace-form {:form "(let [x 1]\n  x)", :origin [2 1]} "1")
+      (t/is (= @set-lines [1 3 ["1 :after"]])))
+
+    (t/testing "inserting below"
+      (nvim/insert-after-form {:form "(let [x 1]\n  x)", :origin [2 1]} [";; => 1"])
+      (t/is (= @set-lines [3 3 [";; => 1"]])))))
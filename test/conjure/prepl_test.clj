@@ -0,0 +1,241 @@
+(ns conjure.prepl-test
+  (:require [clojure.test :as t]
+            [clojure.core.async :as a]
+            [clojure.java.io :as io]
+            [clojure.core.server]
+            [conjure.prepl :as prepl]
+            [conjure.ui :as ui]
+            [conjure.code :as code]
+            [conjure.util :as util])
+  (:import [java.io File]
+           [java.nio.file Files]
+           [java.nio.file.attribute FileAttribute]
+           [java.util.concurrent.locks ReentrantLock]))
+
+(defn- temp-dir []
+  (.toFile (Files/createTempDirectory "conjure" (make-array FileAttribute 0))))
+
+(t/deftest discover
+  (let [root (temp-dir)
+        nested (io/file root "src" "foo")]
+    (.mkdirs nested)
+
+    (t/testing "nothing to find"
+      (t/is (= (prepl/discover (str nested)) nil)))
+
+    (t/testing "closest port files"
+      (spit (io/file root ".prepl-port") "5555\n")
+      (spit (io/file root ".nrepl-port") "7888")
+      (.mkdirs (io/file root ".shadow-cljs"))
+      (spit (io/file root ".shadow-cljs" "nrepl.port") "9000")
+      (spit (io/file root ".shadow-cljs" "socket-repl.port") "9001")
+      (t/is (= (set (prepl/discover (str nested)))
+               #{{:tag :prepl, :protocol :prepl, :port 5555}
+                 {:tag :nrepl, :protocol :nrepl, :port 7888}
+                 {:tag :shadow-nrepl, :protocol :nrepl, :port 9000}
+                 {:tag :shadow-socket-repl, :protocol :repl, :port 9001}})))
+
+    (t/testing "nearer directories win"
+      (spit (io/file nested ".nrepl-port") "1234")
+      (t/is (= (prepl/discover (str nested))
+               [{:tag :nrepl, :protocol :nrepl, :port 1234}])))))
+
+(t/deftest route
+  (let [clj {:tag :clj, :expr #"\.clj$", :priority 0}
+        cljs {:tag :cljs, :expr #"\.cljs$", :priority 0}
+        dev {:tag :dev, :expr #"\.clj$", :priority 10}
+        broken {:tag :broken, :expr #"\.clj$", :priority 20, :reconnecting? true}
+        everything {:tag :aaa, :expr #"", :priority 0}
+        conns [clj cljs dev broken]]
+    (t/is (= (prepl/route conns "foo.cljs") [cljs]))
+    (t/is (= (prepl/route conns "foo.clj") [dev clj]))
+    (t/is (= (prepl/route conns "foo.edn") []))
+    (t/is (= (prepl/route (conj conns everything) "foo.clj") [dev everything clj]))))
+
+(defn- fake-conn
+  "A connection with channels we can drive by hand instead of a socket."
+  [opts]
+  (merge {:tag :fake
+          :lock (ReentrantLock. true)
+          :chans {:eval-chan (a/chan 32)
+                  :ret-chan (a/chan 32)
+                  :read-chan (a/chan 32)}}
+         opts))
+
+(t/deftest await-ret
+  (with-redefs [ui/error (constantly nil)
+                prepl/interrupt! (constantly nil)]
+    (t/testing "in time"
+      (let [conn (fake-conn {:eval-timeout 1000})]
+        (a/>!! (get-in conn [:chans :ret-chan]) {:tag :ret, :val :ok})
+        (t/is (= (prepl/exclusively conn #(prepl/await-ret conn))
+                 {:tag :ret, :val :ok}))))
+
+    (t/testing "a late result doesn't reach the next eval"
+      (let [conn (fake-conn {:eval-timeout 50})
+            ret-chan (get-in conn [:chans :ret-chan])]
+        (future
+          (Thread/sleep 200)
+          (a/>!! ret-chan {:tag :ret, :val :late}))
+        (t/is (= (get-in (prepl/exclusively conn #(prepl/await-ret conn)) [:val 0])
+                 :error))
+        (a/>!! ret-chan {:tag :ret, :val :next})
+        (t/is (= (prepl/exclusively conn #(prepl/await-ret conn))
+                 {:tag :ret, :val :next}))))
+
+    (t/testing "the connection is dropped if the result never arrives"
+      (with-redefs [prepl/interrupt-timeout 50]
+        (let [conn (fake-conn {:eval-timeout 50})
+              {:keys [eval-chan ret-chan]} (:chans conn)]
+          (t/is (= (get-in (prepl/exclusively conn #(prepl/await-ret conn)) [:val 0])
+                   :error))
+          (t/is (nil? (a/<!! eval-chan)))
+          (t/is (nil? (a/<!! ret-chan))))))))
+
+(defn- register! [{:keys [tag] :as conn}]
+  (swap! @#'prepl/conns! assoc tag conn))
+
+(defn- registered [tag]
+  (get @@#'prepl/conns! tag))
+
+(t/deftest beat!
+  (with-redefs [ui/error (constantly nil)
+                prepl/heartbeat-timeout 50
+                prepl/interrupt-timeout 500]
+    (t/testing "answered in time"
+      (let [{:keys [chans] :as conn} (fake-conn {:tag :fake-beat})]
+        (register! conn)
+        (a/>!! (:ret-chan chans) {:tag :ret, :val :conjure/heartbeat})
+        (#'prepl/beat! conn)
+        (t/is (= (a/<!! (:eval-chan chans)) ":conjure/heartbeat\n"))
+        (t/is (false? (:stale? (registered :fake-beat))))
+        (t/is (number? (:latency (registered :fake-beat))))
+        (swap! @#'prepl/conns! dissoc :fake-beat)))
+
+    (t/testing "a slow heartbeat holds the lock until it arrives"
+      (let [{:keys [lock chans] :as conn} (fake-conn {:tag :fake-beat})
+            ret-chan (:ret-chan chans)
+            _ (register! conn)
+            beat (future (#'prepl/beat! conn))]
+        (Thread/sleep 150)
+        (t/is (.isLocked ^ReentrantLock lock))
+        (t/is (true? (:stale? (registered :fake-beat))))
+        (a/>!! ret-chan {:tag :ret, :val :conjure/heartbeat})
+        @beat
+        (t/is (not (.isLocked ^ReentrantLock lock)))
+        (t/is (false? (:stale? (registered :fake-beat))))
+        (t/is (nil? (a/poll! ret-chan)))
+        (swap! @#'prepl/conns! dissoc :fake-beat)))
+
+    (t/testing "a heartbeat that never arrives drops the connection"
+      (let [{:keys [chans] :as conn} (fake-conn {:tag :fake-beat})]
+        (register! conn)
+        (#'prepl/beat! conn)
+        (a/<!! (:eval-chan chans))
+        (t/is (nil? (a/<!! (:eval-chan chans))))
+        (t/is (nil? (a/<!! (:ret-chan chans))))
+        (swap! @#'prepl/conns! dissoc :fake-beat)))))
+
+(t/deftest exclusively-drains-strays
+  (let [conn (fake-conn {})
+        ret-chan (get-in conn [:chans :ret-chan])]
+    (a/>!! ret-chan {:tag :ret, :val :stray})
+    (t/is (= (prepl/exclusively
+               conn
+               (fn []
+                 (a/>!! ret-chan {:tag :ret, :val :mine})
+                 (a/<!! ret-chan)))
+             {:tag :ret, :val :mine}))))
+
+(defn- handle-all!
+  "Run the read-chan handler over the messages, returning everything it
+  displayed and everything it put on the ret-chan along with any errors."
+  [conn outs]
+  (let [results! (atom [])
+        errors! (atom [])
+        {:keys [read-chan ret-chan]} (:chans conn)]
+    (with-redefs [ui/result (fn [{:keys [resp]}] (swap! results! conj resp))
+                  ui/error (fn [& parts] (swap! errors! conj parts))
+                  ui/info (constantly nil)]
+      (run! #(a/>!! read-chan %) outs)
+      (#'prepl/handle! conn)
+      (let [rets (doall (repeatedly (count (filter #(= (:tag %) :ret) outs))
+                                    #(a/<!! ret-chan)))]
+        (a/close! read-chan)
+        {:results @results!
+         :rets rets
+         :errors @errors!}))))
+
+(t/deftest handle!
+  (t/testing "output is coalesced and results go to the ret-chan"
+    (let [{:keys [results rets]} (handle-all! (fake-conn {})
+                                              [{:tag :out, :val "a"}
+                                               {:tag :out, :val "b"}
+                                               {:tag :err, :val "c"}
+                                               {:tag :out, :val "d"}
+                                               {:tag :ret, :val "[:ok 10]"}])]
+      (t/is (= results
+               [{:tag :out, :val "ab"}
+                {:tag :err, :val "c"}
+                {:tag :out, :val "d"}]))
+      (t/is (= rets [{:tag :ret, :val [:ok 10]}]))))
+
+  (t/testing "taps are displayed and parsed"
+    (let [{:keys [results]} (handle-all! (fake-conn {})
+                                         [{:tag :tap, :val "{:a 1}"}
+                                          {:tag :ret, :val "nil"}])]
+      (t/is (= results [{:tag :tap, :val {:a 1}}]))))
+
+  (t/testing "output over the limit is dropped"
+    (let [{:keys [results rets errors]} (handle-all! (fake-conn {:output-limit 10})
+                                                     [{:tag :out, :val "12345"}
+                                                      {:tag :ret, :val "1"}
+                                                      {:tag :out, :val "1234567890"}
+                                                      {:tag :out, :val "more"}
+                                                      {:tag :ret, :val "2"}])]
+      (t/is (= results [{:tag :out, :val "12345"}]))
+      (t/is (= (map :val rets) [1 2]))
+      (t/is (= (count errors) 1)))))
+
+(t/deftest load-broken-file
+  (let [port (util/free-port)
+        server-name (str "conjure-test-" port)
+        broken (doto (File/createTempFile "broken" ".clj")
+                 (spit "(ns broken)\n(defn oops [\n"))]
+    (clojure.core.server/start-server {:name server-name
+                                       :port port
+                                       :accept 'clojure.core.server/io-prepl})
+    (try
+      (with-redefs [ui/result (constantly nil)
+                    ui/error (constantly nil)
+                    ui/info (constantly nil)]
+        (let [conn (fake-conn {})
+              conn (assoc conn :chans (merge (select-keys (:chans conn) [:ret-chan])
+                                             (prepl/connect {:tag :fake
+                                                             :protocol :prepl
+                                                             :host "127.0.0.1"
+                                                             :port port})))
+              {:keys [eval-chan ret-chan]} (:chans conn)]
+          (#'prepl/handle! conn)
+          (a/>!! eval-chan (code/load-file-str (str broken)))
+          (let [[ret] (a/alts!! [ret-chan (a/timeout 10000)])]
+            (t/is (= (:tag ret) :ret))
+            (t/is (:exception ret)))
+          (a/close! eval-chan)))
+      (finally
+        (clojure.core.server/stop-server server-name)
+        (.delete broken)))))
+
+(t/deftest rename!
+  (with-redefs [ui/error (constantly nil)
+                ui/info (constantly nil)]
+    (register! (fake-conn {:tag :fake-a}))
+    (register! (fake-conn {:tag :fake-b}))
+    (try
+      (t/is (false? (prepl/rename! :fake-nope :fake-c)))
+      (t/is (false? (prepl/rename! :fake-a :fake-b)))
+      (t/is (true? (prepl/rename! :fake-a :fake-c)))
+      (t/is (nil? (registered :fake-a)))
+      (t/is (= (:tag (registered :fake-c)) :fake-c))
+      (finally
+        (swap! @#'prepl/conns! dissoc :fake-a :fake-b :fake-c)))))
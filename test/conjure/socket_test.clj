@@ -0,0 +1,94 @@
+(ns conjure.socket-test
+  (:require [clojure.test :as t]
+            [clojure.java.io :as io]
+            [conjure.socket :as socket]
+            [conjure.util :as util])
+  (:import [java.net ServerSocket ProtocolFamily SocketAddress]
+           [java.io File]
+           [java.nio.channels Channels ServerSocketChannel]))
+
+(defn- echo [input output]
+  (let [reader (io/reader input)
+        writer (io/writer output)]
+    (.write writer (str (.readLine reader) "\n"))
+    (.flush writer)))
+
+(defn- echo-server
+  "Accept a single connection and echo the first line back."
+  ^ServerSocket []
+  (let [server (ServerSocket. 0)]
+    (future
+      (with-open [client (.accept server)]
+        (echo (.getInputStream client) (.getOutputStream client))))
+    server))
+
+(defn- round-trip
+  "Send a line through the opened connection and read what comes back."
+  [{:keys [input output close]}]
+  (try
+    (doto (io/writer output)
+      (.write "hello\n")
+      (.flush))
+    (.readLine (io/reader input))
+    (finally
+      (close))))
+
+(t/deftest open-tcp
+  (with-open [server (echo-server)]
+    (t/is (= (round-trip (socket/open {:host "127.0.0.1"
+                                       :port (.getLocalPort server)}))
+             "hello"))))
+
+(t/deftest open-retries
+  (t/testing "gives up after the retries"
+    (let [port (util/free-port)]
+      (t/is (thrown-with-msg? clojure.lang.ExceptionInfo #"after 3 attempts"
+                              (socket/open {:host "127.0.0.1"
+                                            :port port
+                                            :retries 2
+                                            :retry-delay 10
+                                            :connect-timeout 100})))))
+
+  (t/testing "connects once the server turns up"
+    (let [port (util/free-port)
+          server (future
+                   (Thread/sleep 200)
+                   (ServerSocket. port))]
+      (try
+        (let [{:keys [close]} (socket/open {:host "127.0.0.1"
+                                            :port port
+                                            :retries 20
+                                            :retry-delay 50})]
+          (close)
+          (t/is true))
+        (finally
+          (.close ^ServerSocket @server))))))
+
+(defn- unix-echo-server
+  "Like echo-server but on a Unix domain socket, everything is looked up
+  reflectively since it needs Java 16+."
+  ^ServerSocketChannel [address-class path]
+  (let [unix (Enum/valueOf java.net.StandardProtocolFamily "UNIX")
+        address (.invoke (.getMethod ^Class address-class "of" (into-array Class [String]))
+                         nil (object-array [path]))
+        server ^ServerSocketChannel (.invoke (.getMethod ServerSocketChannel "open" (into-array Class [ProtocolFamily]))
+                                             nil (object-array [unix]))]
+    (.bind server ^SocketAddress address)
+    (future
+      (with-open [client (.accept server)]
+        (echo (Channels/newInputStream client) (Channels/newOutputStream client))))
+    server))
+
+(t/deftest open-unix
+  (let [file (File/createTempFile "conjure" ".sock")
+        path (str file)]
+    (.delete file)
+    (if-let [address-class (try
+                             (Class/forName "java.net.UnixDomainSocketAddress")
+                             (catch ClassNotFoundException _
+                               nil))]
+      (with-open [_ (unix-echo-server address-class path)]
+        (t/is (= (round-trip (socket/open {:socket path})) "hello"))
+        (.delete file))
+      (t/is (thrown-with-msg? clojure.lang.ExceptionInfo #"Unix sockets need Java 16\+"
+                              (socket/open {:socket path}))))))
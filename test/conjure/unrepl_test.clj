@@ -0,0 +1,36 @@
+(ns conjure.unrepl-test
+  (:require [clojure.test :as t]
+            [conjure.unrepl :as unrepl]))
+
+(t/deftest ->prepl
+  (t/is (= (unrepl/->prepl 'user=>) nil))
+  (t/is (= (unrepl/->prepl [:prompt {:file "unrepl-session"} nil]) nil))
+  (t/is (= (unrepl/->prepl [:eval [:ok 3] 1]) {:tag :ret, :val "[:ok 3]"}))
+  (t/is (= (unrepl/->prepl [:out "hi\n" 1]) {:tag :out, :val "hi\n"}))
+  (t/is (= (unrepl/->prepl [:err "oh no\n" 1]) {:tag :err, :val "oh no\n"}))
+  (t/is (= (unrepl/->prepl [:exception {:phase :eval} 1])
+           {:tag :ret, :val "[:error {:phase :eval}]"}))
+  (t/is (= (unrepl/->prepl [:eval
+                            [:ok [1 2 (tagged-literal 'unrepl/... {:get '(fetch :G__1)})]]
+                            1])
+           {:tag :ret, :val "[:ok [1 2 #unrepl/... {:get (fetch :G__1)}]]"})))
+
+(def ^:private tail (tagged-literal 'unrepl/... {:get '(fetch :G__1)}))
+
+(t/deftest first-elision
+  (t/is (= (unrepl/first-elision [:a {:b 1}]) nil))
+  (t/is (= (unrepl/first-elision (tagged-literal 'unrepl/ns 'user)) nil))
+  (t/is (identical? (unrepl/first-elision [1 {:a [2 tail]}]) tail)))
+
+(t/deftest splice
+  (t/is (= (unrepl/splice [1 2 tail] tail '(3 4)) [1 2 3 4]))
+  (t/is (= (unrepl/splice {:a [1 tail]} tail '(2)) {:a [1 2]}))
+  (t/is (= (unrepl/splice {:a tail} tail [1 2]) {:a [1 2]}))
+  (t/is (= (unrepl/splice {:a 1, tail nil} tail {:b 2}) {:a 1, :b 2}))
+  (t/is (= (unrepl/splice [1 tail] tail "rest") [1 "rest"]))
+
+  (t/testing "the spliced in expansion can be elided again"
+    (let [next-tail (tagged-literal 'unrepl/... {:get '(fetch :G__2)})
+          value (unrepl/splice [1 tail] tail (list 2 next-tail))]
+      (t/is (= value [1 2 next-tail]))
+      (t/is (identical? (unrepl/first-elision value) next-tail)))))
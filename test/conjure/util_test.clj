@@ -1,6 +1,8 @@
 (ns conjure.util-test
   (:require [clojure.test :as t]
-            [conjure.util :as util]))
+            [conjure.util :as util])
+  (:import [java.io File FileOutputStream]
+           [java.util.zip ZipEntry ZipOutputStream]))
 
 (t/deftest join-words
   (t/is (= (util/join-words nil) ""))
@@ -26,24 +28,67 @@
     (t/is (= (util/splice "" -1 0 "") ""))
     (t/is (= (util/splice "Hello, World!" 7 20 "Conjure?") "Hello, Conjure?"))))
 
+(t/deftest render-template
+  (t/is (= (util/render-template "(foo)" {}) "(foo)"))
+  (t/is (= (util/render-template "(in-ns '{{ns}}) ({{ word }} {{args}})" {:ns "foo", :word "bar"})
+           "(in-ns 'foo) (bar )")))
+
+(t/deftest strip-ansi
+  (t/is (= (util/strip-ansi "plain") "plain"))
+  (t/is (= (util/strip-ansi "\u001b[31mFAIL\u001b[0m in (foo)") "FAIL in (foo)"))
+  (t/is (= (util/strip-ansi "\u001b[1;32mok\u001b[m \u001b]0;title\u0007done") "ok done")))
+
 (t/deftest escape-quotes
   (t/is (= (util/escape-quotes "\"\"") "\\\"\\\"")))
 
 (t/deftest pprint
-  (t/is (util/pprint {:foo :bar}) "{:foo :bar}"))
+  (t/is (util/pprint {:foo :bar}) "{:foo :bar}")
+  (t/is (= (util/pprint [1 2 3 4] {:max-length 2}) "[1 2 ...]"))
+  (t/is (= (util/pprint [1 2] {:width nil}) "[1 2]")))
 
 (t/deftest count-str
   (t/is (= (util/count-str [] "number") "0 numbers"))
   (t/is (= (util/count-str [1] "number") "1 number"))
-  (t/is (= (util/count-str [1 2] "number") "2 numbers")))
+  (t/is (= (util/count-str [1 2] "number") "2 numbers"))
+  (t/is (= (util/count-str 0 "number") "0 numbers"))
+  (t/is (= (util/count-str 1 "number") "1 number")))
+
+(t/deftest duration-str
+  (t/is (= (util/duration-str 0) "0s"))
+  (t/is (= (util/duration-str 42500) "42s"))
+  (t/is (= (util/duration-str 3661000) "1h 1m"))
+  (t/is (= (util/duration-str 3600000) "1h"))
+  (t/is (= (util/duration-str (* 1000 (+ 86400 7200 5))) "1d 2h")))
+
+(t/deftest elapsed-str
+  (t/is (= (util/elapsed-str 12) "12ms"))
+  (t/is (= (util/elapsed-str 1530) "1.53s"))
+  (t/is (= (util/elapsed-str 3661000) "1h 1m")))
+
+(t/deftest timestamp-str
+  (t/is (re-matches #"\d{2}:\d{2}:\d{2}" (util/timestamp-str))))
 
 (t/deftest free-port
   (t/is (number? (util/free-port))))
 
+(t/deftest wait-until
+  (t/is (= (util/wait-until 200 (constantly :done)) :done))
+  (t/is (nil? (util/wait-until 200 (constantly nil)))))
+
+(t/deftest wait-for-port
+  (let [port (util/free-port)]
+    (t/is (false? (util/wait-for-port {:host "127.0.0.1", :port port, :timeout 200})))
+    (with-open [_ (java.net.ServerSocket. port)]
+      (t/is (true? (util/wait-for-port {:host "127.0.0.1", :port port, :timeout 200}))))))
+
 (t/deftest env
   (binding [util/get-env-fn {"CONJURE_FOO_BAR" :baz}]
     (t/is (= (util/env :foo-bar) :baz))))
 
+(t/deftest truncate
+  (t/is (= (util/truncate "hello" 10) ["hello" 0]))
+  (t/is (= (util/truncate "hello world" 5) ["hello" 6])))
+
 (t/deftest regexp?
   (t/is (util/regexp? #"foo"))
   (t/is (not (util/regexp? "foo"))))
@@ -65,3 +110,63 @@
 
 (t/deftest thread
   (t/is (= @(util/thread "adding" (+ 10 10)) 20)))
+
+(t/deftest extract-zipfile
+  (let [jar (File/createTempFile "conjure-test" ".jar")]
+    (try
+      (with-open [out (ZipOutputStream. (FileOutputStream. jar))]
+        (.putNextEntry out (ZipEntry. "foo/bar.clj"))
+        (.write out (.getBytes "(ns foo.bar)")))
+      (let [path (util/extract-zipfile (str "zipfile:" jar "::foo/bar.clj"))]
+        (t/is (= (slurp path) "(ns foo.bar)")))
+      (t/is (nil? (util/extract-zipfile (str "zipfile:" jar "::nope.clj"))))
+      (t/is (nil? (util/extract-zipfile "/foo/bar.clj")))
+      (finally
+        (.delete jar)))))
+
+(t/deftest levenshtein
+  (t/is (= (util/levenshtein "" "") 0))
+  (t/is (= (util/levenshtein "map" "map") 0))
+  (t/is (= (util/levenshtein "map" "mapv") 1))
+  (t/is (= (util/levenshtein "kitten" "sitting") 3))
+  (t/is (= (util/levenshtein "" "abc") 3)))
+
+(t/deftest fuzzy-rank
+  (t/is (= (util/fuzzy-rank {:query "map", :items ["mapcat" "map" "ampersand" "mapv"]})
+           ["map" "mapv" "mapcat" "ampersand"]))
+  (t/is (= (util/fuzzy-rank {:query "map"
+                             :items ["mapcat" "map" "mapv"]
+                             :frequencies {"mapcat" 3}
+                             :limit 2})
+           ["mapcat" "map"]))
+  (t/is (= (util/fuzzy-rank {:query "a", :items [{:word "ab"} {:word "a"}], :word-fn :word})
+           [{:word "a"} {:word "ab"}])))
+
+(t/deftest find-up
+  (let [root (File. (System/getProperty "java.io.tmpdir") (str "conjure-test-" (System/nanoTime)))
+        nested (File. root "a/b")
+        tasks (File. root ".conjure-tasks.edn")]
+    (try
+      (.mkdirs nested)
+      (spit tasks "{}")
+      (t/is (= tasks (util/find-up (str nested) ".conjure-tasks.edn")))
+      (t/is (nil? (util/find-up (str nested) ".nope")))
+      (finally
+        (.delete tasks)
+        (.delete nested)
+        (.delete (.getParentFile nested))
+        (.delete root)))))
+
+(t/deftest append-to-file
+  (let [file (File/createTempFile "conjure-test" ".log")
+        old (File. (str file ".1"))]
+    (try
+      (util/append-to-file {:path (str file), :lines ["foo" "bar"], :max-bytes 10})
+      (util/append-to-file {:path (str file), :lines ["baz"], :max-bytes 10})
+      (t/is (= (slurp file) "foo\nbar\nbaz\n"))
+      (util/append-to-file {:path (str file), :lines ["qux"], :max-bytes 10})
+      (t/is (= (slurp file) "qux\n"))
+      (t/is (= (slurp old) "foo\nbar\nbaz\n"))
+      (finally
+        (.delete file)
+        (.delete old)))))